@@ -0,0 +1,23 @@
+ALTER TABLE user
+ADD COLUMN is_admin BOOLEAN NOT NULL DEFAULT 0;
+
+CREATE TABLE webhook (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL, -- Shared secret used to sign payloads
+    created_at BIGINT NOT NULL -- Timestamp
+);
+
+CREATE TABLE webhook_delivery (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    webhook_id INT NOT NULL,
+    event TEXT NOT NULL, -- e.g. message.created
+    payload TEXT NOT NULL, -- The JSON body sent to the webhook
+    status TEXT NOT NULL DEFAULT 'pending', -- pending, delivered or failed
+    attempts INT NOT NULL DEFAULT 0,
+    next_attempt_at BIGINT NOT NULL, -- Timestamp
+    last_status_code INT, -- The HTTP status of the last attempt, can be null
+    last_error TEXT, -- The error of the last attempt, can be null
+    created_at BIGINT NOT NULL, -- Timestamp
+    FOREIGN KEY(webhook_id) REFERENCES webhook(id) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+ALTER TABLE message
+ADD COLUMN annotations TEXT NOT NULL DEFAULT '[]'; -- JSON array of notes added by message hooks
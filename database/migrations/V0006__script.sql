@@ -0,0 +1,6 @@
+CREATE TABLE script (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    name TEXT NOT NULL,
+    source TEXT NOT NULL, -- Rhai source code
+    created_at BIGINT NOT NULL -- Timestamp
+);
@@ -0,0 +1,8 @@
+-- Events from every instance sharing the database, so each can push the others' to its clients
+CREATE TABLE event (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    instance_id TEXT NOT NULL, -- The instance which published the event
+    name TEXT NOT NULL, -- e.g. message.created
+    payload TEXT NOT NULL, -- The event as JSON
+    created_at BIGINT NOT NULL -- Timestamp
+);
@@ -0,0 +1,12 @@
+-- How many messages have ever been deleted, so clients can tell whether their messages are out of date
+-- along with the newest message's ID
+CREATE TABLE message_deletions (
+    count INT NOT NULL
+);
+
+INSERT INTO message_deletions (count) VALUES (0);
+
+CREATE TRIGGER count_message_deletions AFTER DELETE ON message
+BEGIN
+    UPDATE message_deletions SET count = count + 1;
+END;
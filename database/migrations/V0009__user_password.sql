@@ -0,0 +1,13 @@
+-- Users made before passwords existed have none, and can be claimed by registering under their name
+ALTER TABLE user
+ADD COLUMN password_hash TEXT;
+
+-- Failed sign ins, so passwords can't be guessed quickly
+CREATE TABLE login_failure (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    -- Either "name:<user name>" or "address:<IP address>"
+    subject TEXT NOT NULL,
+    created_at BIGINT NOT NULL
+);
+
+CREATE INDEX login_failure_subject ON login_failure (subject, created_at);
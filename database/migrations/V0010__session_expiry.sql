@@ -0,0 +1,11 @@
+-- Sessions can only be renewed so far past when they were created
+ALTER TABLE session
+ADD COLUMN created_at BIGINT;
+
+-- expires_at was only ever set to when the session was created, so give existing sessions the
+-- default idle lifetime of 7 days from then
+UPDATE session
+SET created_at = expires_at,
+    expires_at = expires_at + 7 * 24 * 60 * 60 * 1000;
+
+CREATE INDEX session_expires_at ON session (expires_at);
@@ -0,0 +1,11 @@
+-- The name compared case-insensitively and Unicode-normalised, so nobody can register a name someone
+-- else has. SQLite can't normalise names, so existing users are given one when the app starts
+ALTER TABLE user
+ADD COLUMN name_key TEXT;
+
+-- What the name looks like, so names which only look the same can be found
+ALTER TABLE user
+ADD COLUMN name_skeleton TEXT;
+
+CREATE UNIQUE INDEX user_name_key ON user (name_key);
+CREATE INDEX user_name_skeleton ON user (name_skeleton);
@@ -0,0 +1,18 @@
+-- What users say about themselves, shown on their profile
+ALTER TABLE user
+ADD COLUMN display_name TEXT;
+
+ALTER TABLE user
+ADD COLUMN pronouns TEXT;
+
+ALTER TABLE user
+ADD COLUMN bio TEXT;
+
+-- Uploaded avatars, users without one are shown an identicon
+CREATE TABLE avatar (
+    user_id INTEGER PRIMARY KEY NOT NULL,
+    content_type TEXT NOT NULL,
+    image BLOB NOT NULL,
+    updated_at BIGINT NOT NULL, -- Timestamp
+    FOREIGN KEY(user_id) REFERENCES user(id)
+);
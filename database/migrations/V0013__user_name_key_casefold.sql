@@ -0,0 +1,5 @@
+-- Keys are now fully case folded rather than lowercased, so "ß" matches "ss". Every user is given
+-- their key again when the app starts
+UPDATE user
+SET name_key = NULL,
+    name_skeleton = NULL;
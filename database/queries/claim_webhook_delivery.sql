@@ -0,0 +1,5 @@
+UPDATE webhook_delivery
+SET next_attempt_at = :lease_until
+WHERE id = :delivery_id
+AND status = 'pending'
+AND next_attempt_at <= :now;
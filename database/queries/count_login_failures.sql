@@ -0,0 +1,3 @@
+SELECT COUNT(*)
+FROM login_failure
+WHERE subject = :subject AND created_at > :since;
@@ -0,0 +1,2 @@
+DELETE FROM avatar
+WHERE user_id = :user_id;
@@ -0,0 +1,2 @@
+DELETE FROM login_failure
+WHERE id = :id;
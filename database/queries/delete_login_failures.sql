@@ -0,0 +1,2 @@
+DELETE FROM login_failure
+WHERE subject = :subject OR created_at <= :before;
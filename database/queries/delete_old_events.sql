@@ -0,0 +1,2 @@
+DELETE FROM event
+WHERE created_at < :before;
@@ -0,0 +1,3 @@
+DELETE
+FROM script
+WHERE id = :script_id;
@@ -0,0 +1,2 @@
+DELETE FROM session
+WHERE id = :session_id;
@@ -0,0 +1,3 @@
+DELETE
+FROM webhook
+WHERE id = :webhook_id;
@@ -0,0 +1,2 @@
+INSERT INTO event (instance_id, name, payload, created_at)
+VALUES (:instance_id, :name, :payload, :created_at);
@@ -0,0 +1 @@
+INSERT INTO login_failure (subject, created_at) VALUES (:subject, :created_at);
@@ -1 +1 @@
-INSERT INTO message (text, created_by_id) VALUES (:message, :user_id);
+INSERT INTO message (text, annotations, created_by_id) VALUES (:message, :annotations, :user_id);
@@ -0,0 +1 @@
+INSERT INTO script (name, source, created_at) VALUES (:name, :source, :created_at);
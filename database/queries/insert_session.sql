@@ -1 +1,2 @@
-INSERT INTO session (id, expires_at) VALUES (:id, :expires_at);
+INSERT INTO session (id, user_id, created_at, expires_at)
+VALUES (:id, :user_id, :created_at, :expires_at);
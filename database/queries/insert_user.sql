@@ -1 +1,2 @@
-INSERT INTO user (name) VALUES (:user_name);
+INSERT INTO user (name, name_key, name_skeleton)
+VALUES (:user_name, :name_key, :name_skeleton);
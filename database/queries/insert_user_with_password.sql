@@ -0,0 +1,2 @@
+INSERT INTO user (name, name_key, name_skeleton, password_hash)
+VALUES (:user_name, :name_key, :name_skeleton, :password_hash);
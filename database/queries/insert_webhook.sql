@@ -0,0 +1 @@
+INSERT INTO webhook (url, secret, created_at) VALUES (:url, :secret, :created_at);
@@ -0,0 +1,2 @@
+INSERT INTO webhook_delivery (webhook_id, event, payload, next_attempt_at, created_at)
+VALUES (:webhook_id, :event, :payload, :now, :now);
@@ -1,4 +1,4 @@
-SELECT message.id, message.text, user.id, user.name
+SELECT message.id, message.text, user.id, user.name, message.annotations
 FROM message
 LEFT JOIN user
 ON message.created_by_id = user.id;
@@ -0,0 +1,3 @@
+SELECT id, name, source
+FROM script
+ORDER BY id;
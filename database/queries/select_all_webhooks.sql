@@ -0,0 +1,3 @@
+SELECT id, url, secret
+FROM webhook
+ORDER BY id;
@@ -0,0 +1,3 @@
+SELECT content_type, image
+FROM avatar
+WHERE user_id = :user_id;
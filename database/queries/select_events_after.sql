@@ -0,0 +1,5 @@
+SELECT id, payload
+FROM event
+WHERE id > :after
+AND instance_id != :instance_id
+ORDER BY id;
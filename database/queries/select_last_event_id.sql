@@ -0,0 +1 @@
+SELECT COALESCE(MAX(id), 0) FROM event;
@@ -0,0 +1,4 @@
+SELECT id, name, source
+FROM script
+ORDER BY id DESC
+LIMIT 1;
@@ -1,4 +1,5 @@
-SELECT id, name
+SELECT user.id, user.name, user.is_admin, user.display_name, user.pronouns, user.bio, avatar.updated_at
 FROM user
-ORDER BY id DESC
+LEFT JOIN avatar ON avatar.user_id = user.id
+ORDER BY user.id DESC
 LIMIT 1;
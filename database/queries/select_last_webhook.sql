@@ -0,0 +1,4 @@
+SELECT id, url, secret
+FROM webhook
+ORDER BY id DESC
+LIMIT 1;
@@ -0,0 +1,7 @@
+SELECT message.id, message.text, user.id, user.name, message.annotations
+FROM message
+LEFT JOIN user
+ON message.created_by_id = user.id
+WHERE message.id > :after
+ORDER BY message.id DESC
+LIMIT :limit;
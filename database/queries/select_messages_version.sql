@@ -0,0 +1,3 @@
+SELECT
+    (SELECT COALESCE(MAX(id), 0) FROM message),
+    (SELECT count FROM message_deletions);
@@ -0,0 +1,16 @@
+SELECT
+    webhook_delivery.id,
+    webhook.url,
+    webhook.secret,
+    webhook_delivery.event,
+    webhook_delivery.payload,
+    webhook_delivery.status,
+    webhook_delivery.attempts,
+    webhook_delivery.last_status_code,
+    webhook_delivery.last_error
+FROM webhook_delivery
+INNER JOIN webhook
+ON webhook_delivery.webhook_id = webhook.id
+WHERE webhook_delivery.status = 'pending'
+AND webhook_delivery.next_attempt_at <= :now
+ORDER BY webhook_delivery.id;
@@ -0,0 +1,15 @@
+SELECT
+    webhook_delivery.id,
+    webhook.url,
+    webhook.secret,
+    webhook_delivery.event,
+    webhook_delivery.payload,
+    webhook_delivery.status,
+    webhook_delivery.attempts,
+    webhook_delivery.last_status_code,
+    webhook_delivery.last_error
+FROM webhook_delivery
+INNER JOIN webhook
+ON webhook_delivery.webhook_id = webhook.id
+ORDER BY webhook_delivery.id DESC
+LIMIT :limit;
@@ -1,3 +1,3 @@
-SELECT id, user_id, expires_at
+SELECT id, user_id, created_at, expires_at
 FROM session
-WHERE id = :session_id;
+WHERE id = :session_id AND expires_at > :now;
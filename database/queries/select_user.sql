@@ -1,3 +1,4 @@
-SELECT id, name
+SELECT user.id, user.name, user.is_admin, user.display_name, user.pronouns, user.bio, avatar.updated_at
 FROM user
-WHERE id = :id;
+LEFT JOIN avatar ON avatar.user_id = user.id
+WHERE user.id = :id;
@@ -0,0 +1,4 @@
+SELECT user.id, user.name, user.is_admin, user.display_name, user.pronouns, user.bio, avatar.updated_at, user.password_hash
+FROM user
+LEFT JOIN avatar ON avatar.user_id = user.id
+WHERE user.name_key = :name_key;
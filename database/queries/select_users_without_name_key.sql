@@ -0,0 +1,5 @@
+SELECT id, name
+FROM user
+WHERE name_key IS NULL
+-- Whoever has claimed a name keeps it, then whoever used it first
+ORDER BY password_hash IS NULL, id;
@@ -0,0 +1,3 @@
+UPDATE session
+SET expires_at = :expires_at
+WHERE id = :session_id;
@@ -0,0 +1,3 @@
+UPDATE user
+SET is_admin = :is_admin
+WHERE id = :id;
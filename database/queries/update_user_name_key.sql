@@ -0,0 +1,5 @@
+-- Names which are already someone else's are left without a key
+UPDATE OR IGNORE user
+SET name_key = :name_key,
+    name_skeleton = :name_skeleton
+WHERE id = :id;
@@ -0,0 +1,3 @@
+UPDATE user
+SET password_hash = :password_hash
+WHERE id = :id AND password_hash IS NULL;
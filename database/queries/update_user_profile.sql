@@ -0,0 +1,5 @@
+UPDATE user
+SET display_name = :display_name,
+    pronouns = :pronouns,
+    bio = :bio
+WHERE id = :id;
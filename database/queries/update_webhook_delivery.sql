@@ -0,0 +1,8 @@
+UPDATE webhook_delivery
+SET
+    status = :status,
+    attempts = :attempts,
+    next_attempt_at = :next_attempt_at,
+    last_status_code = :last_status_code,
+    last_error = :last_error
+WHERE id = :delivery_id;
@@ -0,0 +1,6 @@
+INSERT INTO avatar (user_id, content_type, image, updated_at)
+VALUES (:user_id, :content_type, :image, :updated_at)
+ON CONFLICT (user_id) DO UPDATE
+SET content_type = excluded.content_type,
+    image = excluded.image,
+    updated_at = excluded.updated_at;
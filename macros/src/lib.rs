@@ -7,7 +7,7 @@ use proc_macro::TokenStream;
 
 #[proc_macro]
 pub fn load_query(input: TokenStream) -> TokenStream {
-    const QUERY_LOCATION: &'static str = "database/queries";
+    const QUERY_LOCATION: &str = "database/queries";
 
     if input.is_empty() {
         // No file to read an SQL query from
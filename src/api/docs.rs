@@ -0,0 +1,76 @@
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+use super::views::{CreateMessageResponse, CreateSessionRequest, SessionResponse, StatsResponse};
+use super::{ErrorDetail, ErrorResponse, MessagePage};
+use crate::database::message::Message;
+use crate::database::user::User;
+use crate::websocket::WebSocketStats;
+
+/// Describes how API clients authenticate, using the token from `POST /api/v1/sessions/`
+struct TokenAuth;
+
+impl Modify for TokenAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+
+        components.add_security_scheme(
+            "token",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .description(Some(
+                        "The token returned when signing in. The browser's session_id cookie is also accepted.",
+                    ))
+                    .build(),
+            ),
+        );
+    }
+}
+
+/// The OpenAPI document for every route, served at `/api/openapi.json`
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "JDP Chat",
+        description = "The HTML fragment routes used by the HTMX frontend, and the JSON API for everything else"
+    ),
+    paths(
+        crate::login_view,
+        crate::register_view,
+        crate::logout_view,
+        crate::get_messages_view,
+        crate::create_message_view,
+        crate::delete_message_view,
+        super::views::list_messages,
+        super::views::get_message,
+        super::views::create_message,
+        super::views::delete_message,
+        super::views::get_user,
+        super::views::create_session,
+        super::views::create_user,
+        super::views::get_current_session,
+        super::views::get_stats,
+    ),
+    components(schemas(
+        crate::LoginRequest,
+        crate::CreateMessageRequest,
+        super::views::CreateMessageRequest,
+        CreateMessageResponse,
+        CreateSessionRequest,
+        SessionResponse,
+        StatsResponse,
+        WebSocketStats,
+        Message,
+        MessagePage,
+        User,
+        ErrorResponse,
+        ErrorDetail,
+    )),
+    modifiers(&TokenAuth),
+    tags(
+        (name = "html", description = "Routes returning HTML fragments for HTMX, authenticated with the session_id cookie"),
+        (name = "api", description = "Version 1 of the JSON API"),
+    )
+)]
+pub struct ApiDoc;
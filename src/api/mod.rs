@@ -0,0 +1,259 @@
+use std::fmt::Display;
+
+use axum::async_trait;
+use axum::extract::rejection::JsonRejection;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use axum_extra::extract::CookieJar;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::database::message::Message;
+use crate::database::session::{renew_session, retrieve_session, Session};
+use crate::database::user::User;
+use crate::user::{get_user_from_session, AccountError};
+use crate::AppState;
+
+pub mod docs;
+pub mod views;
+
+/// An error returned from the API as JSON, i.e.
+/// `{ "error": { "code": "not_found", "message": "Message 1 does not exist" } }`
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ErrorDetail {
+    /// A short, stable description of the error, i.e. not_found
+    #[schema(example = "not_found")]
+    code: &'static str,
+    /// A human readable description of the error
+    message: String,
+}
+
+/// The body of every error response
+#[derive(Serialize, ToSchema)]
+pub struct ErrorResponse {
+    error: ErrorDetail,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "bad_request", message)
+    }
+
+    pub fn unauthorized() -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, "unauthorized", "Not logged in")
+    }
+
+    pub fn forbidden() -> Self {
+        Self::new(StatusCode::FORBIDDEN, "forbidden", "Permission denied")
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+
+    /// Logs an unexpected error, which isn't shown to clients as it can give away internals
+    pub fn internal(error: impl Display) -> Self {
+        eprintln!("API error: {}", error);
+
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            "Internal error",
+        )
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorResponse {
+            error: ErrorDetail {
+                code: self.code,
+                message: self.message,
+            },
+        };
+
+        (self.status, Json(body)).into_response()
+    }
+}
+
+impl From<rusqlite::Error> for ApiError {
+    fn from(error: rusqlite::Error) -> Self {
+        Self::internal(error)
+    }
+}
+
+impl From<AccountError> for ApiError {
+    fn from(error: AccountError) -> Self {
+        let message = error.message();
+
+        match error {
+            AccountError::InvalidCredentials => {
+                Self::new(StatusCode::UNAUTHORIZED, "invalid_credentials", message)
+            }
+            AccountError::RateLimited => {
+                Self::new(StatusCode::TOO_MANY_REQUESTS, "rate_limited", message)
+            }
+            AccountError::NameTaken => Self::new(StatusCode::CONFLICT, "name_taken", message),
+            AccountError::NameConfusable => {
+                Self::new(StatusCode::CONFLICT, "name_confusable", message)
+            }
+            AccountError::InvalidName(_) | AccountError::InvalidPassword(_) => {
+                Self::bad_request(message)
+            }
+            AccountError::Database(e) => Self::internal(e),
+        }
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::new(rejection.status(), "invalid_body", rejection.body_text())
+    }
+}
+
+/// A page of results, along with the cursor to pass as `before` to get the next page
+#[derive(Serialize, ToSchema)]
+#[aliases(MessagePage = Page<Message>)]
+pub struct Page<T> {
+    pub data: Vec<T>,
+    /// Passed as `before` to get the next page, or null if this is the last page
+    pub next_before: Option<i32>,
+}
+
+/// Finds the session a request was made with
+///
+/// API clients send their session ID as a bearer token, i.e. `Authorization: Bearer <session id>`,
+/// but the browser's session cookie works too
+fn find_session(parts: &Parts) -> Result<Option<Session>, ApiError> {
+    let token = bearer_token(parts).or_else(|| {
+        CookieJar::from_headers(&parts.headers)
+            .get("session_id")
+            .map(|cookie| cookie.value().to_string())
+    });
+
+    load_session(token)
+}
+
+/// The session ID sent as `Authorization: Bearer <session id>`, if there is one
+fn bearer_token(parts: &Parts) -> Option<String> {
+    parts
+        .headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+}
+
+fn load_session(token: Option<String>) -> Result<Option<Session>, ApiError> {
+    let Some(token) = token else {
+        return Ok(None);
+    };
+
+    let mut session = retrieve_session(&token)?;
+
+    // Tokens in use are kept alive, the same as cookies
+    if let Some(session) = session.as_mut() {
+        renew_session(session)?;
+    }
+
+    Ok(session)
+}
+
+/// The logged in user making an API request
+///
+/// Unlike `ExtractSession`, a session is never created, and requests without a logged in user are
+/// rejected
+pub struct ApiUser(pub User);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ApiUser
+where
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let session = find_session(parts)?.ok_or_else(ApiError::unauthorized)?;
+        let user = get_user_from_session(&session)?.ok_or_else(ApiError::unauthorized)?;
+
+        Ok(ApiUser(user))
+    }
+}
+
+/// The logged in user making a request with a bearer token, like `ApiUser` but ignoring the
+/// session cookie
+///
+/// Browsers send cookies along with requests other sites make, so endpoints which can't tell
+/// those apart from the app's own only take tokens
+pub struct BearerUser(pub User);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for BearerUser
+where
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let session = load_session(bearer_token(parts))?.ok_or_else(ApiError::unauthorized)?;
+        let user = get_user_from_session(&session)?.ok_or_else(ApiError::unauthorized)?;
+
+        Ok(BearerUser(user))
+    }
+}
+
+/// Requires an API request to be made by an admin, like `ApiUser` but rejecting everyone else
+pub struct ApiAdmin;
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ApiAdmin
+where
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let ApiUser(user) = ApiUser::from_request_parts(parts, state).await?;
+
+        match user.is_admin {
+            true => Ok(ApiAdmin),
+            false => Err(ApiError::forbidden()),
+        }
+    }
+}
+
+/// The routes for version 1 of the API, nested under `/api/v1`
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/messages/",
+            get(views::list_messages).post(views::create_message),
+        )
+        .route(
+            "/messages/:message_id/",
+            get(views::get_message).delete(views::delete_message),
+        )
+        .route("/users/", post(views::create_user))
+        .route("/users/:user_id/", get(views::get_user))
+        .route("/sessions/", post(views::create_session))
+        .route("/sessions/current/", get(views::get_current_session))
+        .route("/stats/", get(views::get_stats))
+}
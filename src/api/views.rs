@@ -0,0 +1,345 @@
+use std::net::SocketAddr;
+
+use axum::extract::rejection::{JsonRejection, QueryRejection};
+use axum::extract::{ConnectInfo, Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use super::{ApiAdmin, ApiError, ApiUser, Page};
+use crate::chat::{post_message, remove_message, DeleteMessageError, PostMessageError};
+use crate::database::message::{get_message_by_id, get_messages_page, Message};
+use crate::database::session::{create_session as create_db_session, set_session_user};
+use crate::database::user::{retrieve_user, User};
+use crate::events::Event;
+use crate::user::{authenticate, register_user};
+use crate::websocket::{client_address, WebSocketStats};
+use crate::AppState;
+
+/// How many messages are returned when a page size isn't given
+pub const DEFAULT_PAGE_SIZE: u32 = 50;
+/// The most messages which can be returned at once
+pub const MAX_PAGE_SIZE: u32 = 100;
+
+#[derive(Deserialize, IntoParams)]
+pub struct ListMessagesQuery {
+    /// Only return messages older than this message ID
+    before: Option<i32>,
+    /// How many messages to return, between 1 and 100
+    limit: Option<u32>,
+}
+
+///
+/// GET request to list messages, newest first
+///
+#[utoipa::path(
+    get,
+    path = "/api/v1/messages/",
+    tag = "api",
+    params(ListMessagesQuery),
+    responses(
+        (status = 200, description = "A page of messages, newest first", body = MessagePage),
+        (status = 400, description = "Invalid query", body = ErrorResponse),
+    )
+)]
+pub async fn list_messages(
+    query: Result<Query<ListMessagesQuery>, QueryRejection>,
+) -> Result<Json<Page<Message>>, ApiError> {
+    let Query(query) = query.map_err(|e| ApiError::bad_request(e.body_text()))?;
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    let messages = get_messages_page(query.before, limit)?;
+
+    // A full page means there could be older messages
+    let next_before = match messages.len() == limit as usize {
+        true => messages.last().map(|message| message.id),
+        false => None,
+    };
+
+    Ok(Json(Page {
+        data: messages,
+        next_before,
+    }))
+}
+
+///
+/// GET request to load a single message
+///
+#[utoipa::path(
+    get,
+    path = "/api/v1/messages/{message_id}/",
+    tag = "api",
+    params(("message_id" = i32, Path, description = "The message's ID")),
+    responses(
+        (status = 200, description = "The message", body = Message),
+        (status = 404, description = "No message has the ID", body = ErrorResponse),
+    )
+)]
+pub async fn get_message(Path(message_id): Path<i32>) -> Result<Json<Message>, ApiError> {
+    match get_message_by_id(message_id)? {
+        Some(message) => Ok(Json(message)),
+        None => Err(ApiError::not_found(format!(
+            "Message {message_id} does not exist"
+        ))),
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+#[schema(as = api::CreateMessageRequest)]
+pub struct CreateMessageRequest {
+    /// The text of the message
+    #[schema(example = "Hello everyone!")]
+    message: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CreateMessageResponse {
+    message: Message,
+    /// Messages posted by message hooks in reply to the new message
+    replies: Vec<Message>,
+}
+
+///
+/// POST request to create a new message as the logged in user
+///
+#[utoipa::path(
+    post,
+    path = "/api/v1/messages/",
+    tag = "api",
+    request_body = api::CreateMessageRequest,
+    security(("token" = [])),
+    responses(
+        (status = 201, description = "The message was created", body = CreateMessageResponse),
+        (status = 400, description = "The message is invalid", body = ErrorResponse),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 422, description = "A message hook rejected the message", body = ErrorResponse),
+    )
+)]
+pub async fn create_message(
+    State(state): State<AppState>,
+    ApiUser(user): ApiUser,
+    request: Result<Json<CreateMessageRequest>, JsonRejection>,
+) -> Result<(StatusCode, Json<CreateMessageResponse>), ApiError> {
+    let Json(request) = request?;
+
+    let posted = post_message(&request.message, &user, state.message_hooks, &state.events).await;
+
+    match posted {
+        Ok((message, replies)) => Ok((
+            StatusCode::CREATED,
+            Json(CreateMessageResponse { message, replies }),
+        )),
+        Err(PostMessageError::Invalid) => Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_message",
+            "Invalid message",
+        )),
+        Err(PostMessageError::Rejected(reason)) => Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "rejected",
+            reason,
+        )),
+        Err(PostMessageError::Database(e)) => Err(e.into()),
+    }
+}
+
+///
+/// DELETE request to delete one of the logged in user's messages
+///
+#[utoipa::path(
+    delete,
+    path = "/api/v1/messages/{message_id}/",
+    tag = "api",
+    params(("message_id" = i32, Path, description = "The message's ID")),
+    security(("token" = [])),
+    responses(
+        (status = 204, description = "The message was deleted"),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "The message belongs to someone else", body = ErrorResponse),
+        (status = 404, description = "No message has the ID", body = ErrorResponse),
+    )
+)]
+pub async fn delete_message(
+    State(state): State<AppState>,
+    ApiUser(user): ApiUser,
+    Path(message_id): Path<i32>,
+) -> Result<StatusCode, ApiError> {
+    match remove_message(message_id, &user, &state.events).await {
+        Ok(_) => Ok(StatusCode::NO_CONTENT),
+        Err(DeleteMessageError::NotFound) => Err(ApiError::not_found(format!(
+            "Message {message_id} does not exist"
+        ))),
+        Err(DeleteMessageError::Forbidden) => Err(ApiError::forbidden()),
+        Err(DeleteMessageError::Database(e)) => Err(e.into()),
+    }
+}
+
+///
+/// GET request to load a user
+///
+#[utoipa::path(
+    get,
+    path = "/api/v1/users/{user_id}/",
+    tag = "api",
+    params(("user_id" = i32, Path, description = "The user's ID")),
+    responses(
+        (status = 200, description = "The user", body = User),
+        (status = 404, description = "No user has the ID", body = ErrorResponse),
+    )
+)]
+pub async fn get_user(Path(user_id): Path<i32>) -> Result<Json<User>, ApiError> {
+    match retrieve_user(user_id)? {
+        Some(user) => Ok(Json(user)),
+        None => Err(ApiError::not_found(format!(
+            "User {user_id} does not exist"
+        ))),
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateSessionRequest {
+    /// The name to post messages under
+    #[schema(example = "Gamer")]
+    name: String,
+    #[schema(example = "correct horse battery staple")]
+    password: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SessionResponse {
+    /// Sent as `Authorization: Bearer <token>` to authenticate later requests
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    user: User,
+}
+
+/// Starts a new session signed in as the user
+fn sign_in(state: &AppState, user: User) -> Result<(StatusCode, Json<SessionResponse>), ApiError> {
+    let session = create_db_session()?;
+    state.events.publish(Event::SessionCreated);
+
+    set_session_user(&session.id, user.id)?;
+    state.events.publish(Event::UserLoggedIn {
+        user_id: user.id,
+        session_id: session.id.clone(),
+        previous_session_id: None,
+    });
+
+    Ok((
+        StatusCode::CREATED,
+        Json(SessionResponse {
+            token: Some(session.id),
+            user,
+        }),
+    ))
+}
+
+///
+/// POST request to sign in, returning a token for the new session
+///
+#[utoipa::path(
+    post,
+    path = "/api/v1/sessions/",
+    tag = "api",
+    request_body = CreateSessionRequest,
+    responses(
+        (status = 201, description = "Signed in", body = SessionResponse),
+        (status = 401, description = "The name or password is incorrect", body = ErrorResponse),
+        (status = 429, description = "Too many sign ins failed recently", body = ErrorResponse),
+    )
+)]
+pub async fn create_session(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    request: Result<Json<CreateSessionRequest>, JsonRejection>,
+) -> Result<(StatusCode, Json<SessionResponse>), ApiError> {
+    let Json(request) = request?;
+
+    let address = client_address(&headers, peer);
+    let user = authenticate(&request.name, &request.password, address).await?;
+
+    sign_in(&state, user)
+}
+
+///
+/// POST request to register, returning a token for a session signed in as the new user
+///
+/// Users from before passwords existed can only be claimed with a token already signed in as them
+///
+#[utoipa::path(
+    post,
+    path = "/api/v1/users/",
+    tag = "api",
+    request_body = CreateSessionRequest,
+    responses(
+        (status = 201, description = "Registered and signed in", body = SessionResponse),
+        (status = 400, description = "The name or password is invalid", body = ErrorResponse),
+        (status = 409, description = "The name is taken", body = ErrorResponse),
+    )
+)]
+pub async fn create_user(
+    State(state): State<AppState>,
+    signed_in: Option<ApiUser>,
+    request: Result<Json<CreateSessionRequest>, JsonRejection>,
+) -> Result<(StatusCode, Json<SessionResponse>), ApiError> {
+    let Json(request) = request?;
+
+    let session_user_id = signed_in.map(|ApiUser(user)| user.id);
+    let user = register_user(
+        &request.name,
+        &request.password,
+        session_user_id,
+        state.message_hooks,
+    )
+    .await?;
+
+    sign_in(&state, user)
+}
+
+///
+/// GET request to load the session the request was made with
+///
+#[utoipa::path(
+    get,
+    path = "/api/v1/sessions/current/",
+    tag = "api",
+    security(("token" = [])),
+    responses(
+        (status = 200, description = "Who the session is signed in as", body = SessionResponse),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+    )
+)]
+pub async fn get_current_session(ApiUser(user): ApiUser) -> Json<SessionResponse> {
+    Json(SessionResponse { token: None, user })
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct StatsResponse {
+    websockets: WebSocketStats,
+}
+
+///
+/// GET request to see how many clients are connected, for monitoring
+///
+#[utoipa::path(
+    get,
+    path = "/api/v1/stats/",
+    tag = "api",
+    security(("token" = [])),
+    responses(
+        (status = 200, description = "Connection counts", body = StatsResponse),
+        (status = 401, description = "Not logged in", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+    )
+)]
+pub async fn get_stats(State(state): State<AppState>, _: ApiAdmin) -> Json<StatsResponse> {
+    let websockets = state.websocket_handler.lock().unwrap().stats();
+
+    Json(StatsResponse { websockets })
+}
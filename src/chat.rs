@@ -0,0 +1,132 @@
+use rusqlite::Error;
+use tokio::task::spawn_blocking;
+
+use crate::database::message::{
+    can_user_delete, create_message, delete_message, get_message_by_id, Message,
+};
+use crate::database::user::User;
+use crate::events::{Event, EventBus};
+use crate::hooks::MessageHooks;
+use crate::validators::validate_message;
+use crate::webhook::dispatch_event;
+
+/// Publishes a chat event, queueing its webhook deliveries first
+///
+/// Deliveries are queued here rather than by a subscriber, so a subscriber falling behind the event
+/// bus can't lose them
+fn publish(events: &EventBus, event: Event) {
+    if let Err(e) = dispatch_event(&event) {
+        eprintln!("Webhook dispatch error: {}", e);
+    }
+
+    events.publish(event);
+}
+
+/// Why a message couldn't be posted
+pub enum PostMessageError {
+    /// The message failed validation
+    Invalid,
+    /// A message hook rejected the message, with the reason it gave
+    Rejected(String),
+    Database(Error),
+}
+
+/// Posts a message on behalf of a user, no matter how they sent it
+///
+/// The message is run through every message hook and published on the event bus, and the message
+/// is returned along with any replies hooks made to it. Hooks like scripts can take a while, so
+/// this runs on the blocking thread pool rather than holding up the caller's task
+pub async fn post_message(
+    text: &str,
+    author: &User,
+    message_hooks: &'static MessageHooks,
+    events: &EventBus,
+) -> Result<(Message, Vec<Message>), PostMessageError> {
+    let text = text.to_string();
+    let author = author.clone();
+    let events = events.clone();
+
+    spawn_blocking(move || create_with_hooks(&text, &author, message_hooks, &events))
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
+
+fn create_with_hooks(
+    text: &str,
+    author: &User,
+    message_hooks: &MessageHooks,
+    events: &EventBus,
+) -> Result<(Message, Vec<Message>), PostMessageError> {
+    validate_message(text).map_err(|_| PostMessageError::Invalid)?;
+
+    // Give hooks a chance to reject or rewrite the message
+    let draft = message_hooks
+        .before_create(text, author)
+        .map_err(PostMessageError::Rejected)?;
+
+    validate_message(&draft.text).map_err(|_| PostMessageError::Invalid)?;
+
+    // Add the new message to the list of messages
+    let message = create_message(&draft.text, &draft.annotations, author.id)
+        .map_err(PostMessageError::Database)?;
+
+    // Let hooks reply to the message
+    let replies = match message_hooks.after_create(&message, author) {
+        Ok(replies) => replies,
+        Err(e) => {
+            eprintln!("Message hook reply error: {}", e);
+            vec![]
+        }
+    };
+
+    // Let everyone know a new message was created
+    for message in std::iter::once(&message).chain(replies.iter()) {
+        publish(events, Event::MessageCreated(message.clone()));
+    }
+
+    Ok((message, replies))
+}
+
+/// Why a message couldn't be deleted
+pub enum DeleteMessageError {
+    NotFound,
+    /// The user isn't allowed to delete the message
+    Forbidden,
+    Database(Error),
+}
+
+/// Deletes a message on behalf of a user, returning the deleted message
+///
+/// The user must have created the message
+pub async fn remove_message(
+    message_id: i32,
+    user: &User,
+    events: &EventBus,
+) -> Result<Message, DeleteMessageError> {
+    let user = user.clone();
+    let events = events.clone();
+
+    spawn_blocking(move || delete_as(message_id, &user, &events))
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
+
+fn delete_as(
+    message_id: i32,
+    user: &User,
+    events: &EventBus,
+) -> Result<Message, DeleteMessageError> {
+    let message = get_message_by_id(message_id)
+        .map_err(DeleteMessageError::Database)?
+        .ok_or(DeleteMessageError::NotFound)?;
+
+    if !can_user_delete(&message, user) {
+        return Err(DeleteMessageError::Forbidden);
+    }
+
+    delete_message(message_id).map_err(DeleteMessageError::Database)?;
+
+    publish(events, Event::MessageDeleted(message.clone()));
+
+    Ok(message)
+}
@@ -1 +1,5 @@
-pub const DB_PATH: &'static str = "database/jdp-db.db";
+#[cfg(not(test))]
+pub const DB_PATH: &str = "database/jdp-db.db";
+/// Tests get a database of their own, which is started afresh every run
+#[cfg(test)]
+pub const DB_PATH: &str = "database/jdp-test.db";
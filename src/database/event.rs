@@ -0,0 +1,77 @@
+use macros::load_query;
+use rusqlite::{named_params, params, Connection, Error, Result, Row};
+
+use super::constants::DB_PATH;
+
+/// An event published by another instance, as JSON
+pub struct StoredEvent {
+    pub id: i32,
+    pub payload: String,
+}
+
+impl<'stmt> TryFrom<&'stmt Row<'stmt>> for StoredEvent {
+    type Error = Error;
+
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        let id = row.get(0)?;
+        let payload = row.get(1)?;
+
+        Ok(Self { id, payload })
+    }
+}
+
+/// Records an event for every other instance to pick up
+pub fn create_event(
+    instance_id: &str,
+    name: &str,
+    payload: &str,
+    created_at: u64,
+) -> Result<(), Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    conn.execute(
+        load_query!("insert_event.sql"),
+        named_params! {
+            ":instance_id": instance_id,
+            ":name": name,
+            ":payload": payload,
+            ":created_at": created_at,
+        },
+    )?;
+
+    Ok(())
+}
+
+/// The ID of the newest event, or 0 if there are none
+pub fn get_last_event_id() -> Result<i32, Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    conn.query_row(load_query!("select_last_event_id.sql"), params![], |row| {
+        row.get(0)
+    })
+}
+
+/// Retrieves every event after `after` which was published by another instance, oldest first
+pub fn get_events_after(after: i32, instance_id: &str) -> Result<Vec<StoredEvent>, Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    let mut statement = conn.prepare(load_query!("select_events_after.sql"))?;
+    let events = statement
+        .query_map(
+            named_params! { ":after": after, ":instance_id": instance_id },
+            |row| row.try_into(),
+        )?
+        .collect::<Result<Vec<StoredEvent>, Error>>()?;
+
+    Ok(events)
+}
+
+/// Removes events which every instance has had plenty of time to pick up
+pub fn delete_events_before(before: u64) -> Result<usize, Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    conn.execute(
+        load_query!("delete_old_events.sql"),
+        named_params! { ":before": before },
+    )
+}
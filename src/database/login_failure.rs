@@ -0,0 +1,80 @@
+use macros::load_query;
+use rusqlite::{named_params, Connection, Error, Result, TransactionBehavior};
+
+use super::constants::DB_PATH;
+
+/// Records a sign in as failed against each name or address before its password is checked, unless
+/// one of them has already failed as many times since `since` as it's allowed
+///
+/// Checked and recorded in one transaction, so sign ins made at the same time can't all get in
+/// under the limit. Returns the records to forget if the sign in works, or nothing if it's refused
+pub fn record_login_attempt(
+    subjects: &[(String, u32)],
+    since: u64,
+    created_at: u64,
+) -> Result<Option<Vec<i64>>, Error> {
+    let mut conn = Connection::open(DB_PATH)?;
+    let transaction = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+    for (subject, limit) in subjects {
+        let failures: u32 = transaction.query_row(
+            load_query!("count_login_failures.sql"),
+            named_params! {
+                ":subject": subject,
+                ":since": since,
+            },
+            |row| row.get(0),
+        )?;
+
+        if failures >= *limit {
+            return Ok(None);
+        }
+    }
+
+    let mut attempt = Vec::with_capacity(subjects.len());
+
+    for (subject, _) in subjects {
+        transaction.execute(
+            load_query!("insert_login_failure.sql"),
+            named_params! {
+                ":subject": subject,
+                ":created_at": created_at,
+            },
+        )?;
+
+        attempt.push(transaction.last_insert_rowid());
+    }
+
+    transaction.commit()?;
+
+    Ok(Some(attempt))
+}
+
+/// Forgets a sign in recorded by `record_login_attempt`, once it turns out not to have failed
+pub fn forget_login_attempt(attempt: &[i64]) -> Result<(), Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    for id in attempt {
+        conn.execute(
+            load_query!("delete_login_failure.sql"),
+            named_params! { ":id": id },
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Forgets a name or address's failed sign ins, along with everyone's from before `before`
+pub fn delete_login_failures(subject: &str, before: u64) -> Result<(), Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    conn.execute(
+        load_query!("delete_login_failures.sql"),
+        named_params! {
+            ":subject": subject,
+            ":before": before,
+        },
+    )?;
+
+    Ok(())
+}
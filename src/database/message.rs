@@ -1,23 +1,37 @@
+use async_graphql::SimpleObject;
 use macros::load_query;
+use rusqlite::types::Type;
 use rusqlite::{named_params, params, Connection, Error, OptionalExtension, Result, Row};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
 use super::{constants::DB_PATH, user::User};
 
-#[derive(Clone)]
+#[derive(Clone, Deserialize, Serialize, SimpleObject, ToSchema)]
+#[graphql(complex)]
 pub struct Message {
     pub id: i32,
     pub text: String,
     pub author_id: i32,
     pub author_name: String,
+    /// Notes attached to the message by message hooks
+    pub annotations: Vec<String>,
 }
 
 impl Message {
-    pub fn new(id: i32, text: String, author_id: i32, author_name: String) -> Self {
+    pub fn new(
+        id: i32,
+        text: String,
+        author_id: i32,
+        author_name: String,
+        annotations: Vec<String>,
+    ) -> Self {
         Self {
             id,
             text,
             author_id,
             author_name,
+            annotations,
         }
     }
 }
@@ -30,8 +44,13 @@ impl<'stmt> TryFrom<&'stmt Row<'stmt>> for Message {
         let text = row.get(1)?;
         let author_id = row.get(2)?;
         let author_name = row.get(3)?;
+        let annotations: String = row.get(4)?;
 
-        Ok(Self::new(id, text, author_id, author_name))
+        // Annotations are stored as a JSON array
+        let annotations = serde_json::from_str(&annotations)
+            .map_err(|e| Error::FromSqlConversionFailure(4, Type::Text, Box::new(e)))?;
+
+        Ok(Self::new(id, text, author_id, author_name, annotations))
     }
 }
 
@@ -39,14 +58,23 @@ impl<'stmt> TryFrom<&'stmt Row<'stmt>> for Message {
 ///     
 /// # Arguments
 /// * `message` - The message to be created
+/// * `annotations` - Notes attached to the message by message hooks
 ///
-pub fn create_message(message: &str, user_id: i32) -> Result<Message, Error> {
+pub fn create_message(
+    message: &str,
+    annotations: &[String],
+    user_id: i32,
+) -> Result<Message, Error> {
     let conn = Connection::open(DB_PATH)?;
 
+    let annotations = serde_json::to_string(annotations)
+        .map_err(|e| Error::ToSqlConversionFailure(Box::new(e)))?;
+
     conn.execute(
         load_query!("insert_message.sql"),
         named_params! {
             ":message": message,
+            ":annotations": annotations,
             ":user_id": user_id
         },
     )?;
@@ -71,6 +99,68 @@ pub fn get_messages() -> Result<Vec<Message>, Error> {
     Ok(messages)
 }
 
+/// Changes whenever a message is created or deleted
+///
+/// Message IDs are never reused, so the newest ID and how many have been deleted tell every list of
+/// messages apart
+#[derive(Debug, PartialEq)]
+pub struct MessagesVersion {
+    pub last_message_id: i32,
+    pub deletions: i64,
+}
+
+/// Retrieves the current version of the messages, without loading any of them
+pub fn get_messages_version() -> Result<MessagesVersion, Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    conn.query_row(
+        load_query!("select_messages_version.sql"),
+        params![],
+        |row| {
+            Ok(MessagesVersion {
+                last_message_id: row.get(0)?,
+                deletions: row.get(1)?,
+            })
+        },
+    )
+}
+
+/// Retrieves a page of messages, newest first
+///
+/// # Arguments
+/// * `before` - Only messages with an ID lower than this are returned, or the newest if not given
+/// * `limit` - The most messages to return
+///
+pub fn get_messages_page(before: Option<i32>, limit: u32) -> Result<Vec<Message>, Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    let mut statement = conn.prepare(load_query!("select_messages_page.sql"))?;
+    let messages = statement
+        .query_map(
+            named_params! { ":before": before, ":limit": limit },
+            |row| row.try_into(),
+        )?
+        .collect::<Result<Vec<Message>, Error>>()?;
+
+    Ok(messages)
+}
+
+/// Retrieves the newest messages created after a message, oldest first
+pub fn get_messages_after(after: i32, limit: u32) -> Result<Vec<Message>, Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    let mut statement = conn.prepare(load_query!("select_messages_after.sql"))?;
+    let mut messages = statement
+        .query_map(named_params! { ":after": after, ":limit": limit }, |row| {
+            row.try_into()
+        })?
+        .collect::<Result<Vec<Message>, Error>>()?;
+
+    messages.reverse();
+
+    Ok(messages)
+}
+
 /// Retrieves a specific message with a given ID
 pub fn get_message_by_id(id: i32) -> Result<Option<Message>, Error> {
     let conn = Connection::open(DB_PATH)?;
@@ -97,3 +187,18 @@ pub fn delete_message(message_id: i32) -> Result<usize, Error> {
 pub fn can_user_delete(message: &Message, user: &User) -> bool {
     message.author_id == user.id
 }
+
+/// Returns whether a message mentions a user, i.e. "Hello @Gamer"
+///
+/// The mention has to end where the name does, so "@Gamer" doesn't mention "Gamer" in "@Gamers"
+pub fn mentions_user(message: &Message, user: &User) -> bool {
+    let mention = format!("@{}", user.name.to_lowercase());
+    let text = message.text.to_lowercase();
+
+    text.match_indices(&mention).any(|(start, _)| {
+        text[start + mention.len()..]
+            .chars()
+            .next()
+            .is_none_or(|next| !next.is_alphanumeric() && next != '_')
+    })
+}
@@ -3,9 +3,13 @@ use rusqlite::{Connection, Error, Result};
 use constants::DB_PATH;
 
 mod constants;
+pub mod event;
+pub mod login_failure;
 pub mod message;
+pub mod script;
 pub mod session;
 pub mod user;
+pub mod webhook;
 
 // Embed migrations into code here
 mod embedded {
@@ -21,5 +25,21 @@ pub fn run_migrations() -> Result<(), Error> {
 
     embedded::migrations::runner().run(&mut conn).unwrap();
 
+    // SQLite can't normalise names, so the migration adding their keys leaves that to us
+    user::backfill_name_keys()?;
+
     Ok(())
 }
+
+/// Deletes the tests' database, so each run starts with an empty one
+#[cfg(test)]
+pub fn delete_test_database() {
+    for suffix in ["", "-journal", "-wal", "-shm"] {
+        match std::fs::remove_file(format!("{DB_PATH}{suffix}")) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                panic!("Could not delete the test database: {}", e)
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use macros::load_query;
+use rusqlite::{named_params, params, Connection, Error, Result, Row};
+
+use super::constants::DB_PATH;
+
+pub struct Script {
+    pub id: i32,
+    pub name: String,
+    pub source: String,
+}
+
+impl Script {
+    pub fn new(id: i32, name: String, source: String) -> Self {
+        Self { id, name, source }
+    }
+}
+
+impl<'stmt> TryFrom<&'stmt Row<'stmt>> for Script {
+    type Error = Error;
+
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        let id = row.get(0)?;
+        let name = row.get(1)?;
+        let source = row.get(2)?;
+
+        Ok(Self::new(id, name, source))
+    }
+}
+
+/// Saves a new bot script
+pub fn create_script(name: &str, source: &str, created_at: u64) -> Result<Script, Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    conn.execute(
+        load_query!("insert_script.sql"),
+        named_params! { ":name": name, ":source": source, ":created_at": created_at },
+    )?;
+
+    // Get the created script
+    let mut statement = conn.prepare(load_query!("select_last_script.sql"))?;
+
+    statement.query_row(params![], |row| row.try_into())
+}
+
+/// Retrieves all scripts, in the order they were uploaded
+pub fn get_scripts() -> Result<Vec<Script>, Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    let mut statement = conn.prepare(load_query!("select_all_scripts.sql"))?;
+    let scripts = statement
+        .query_map(params![], |row| row.try_into())?
+        .collect::<Result<Vec<Script>, Error>>()?;
+
+    Ok(scripts)
+}
+
+pub fn delete_script(script_id: i32) -> Result<usize, Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    conn.execute(
+        load_query!("delete_script.sql"),
+        named_params! { ":script_id": script_id },
+    )
+}
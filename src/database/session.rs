@@ -1,19 +1,82 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::Duration;
 
 use macros::load_query;
-use rusqlite::{named_params, params, Connection, Error, OptionalExtension, Result, Row};
+use rusqlite::{named_params, Connection, Error, OptionalExtension, Result, Row};
 use uuid::Uuid;
 
 use super::constants::DB_PATH;
+use crate::time::now;
+
+/// How long sessions last unless the lifetimes are configured
+pub const DEFAULT_IDLE_LIFETIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+pub const DEFAULT_ABSOLUTE_LIFETIME: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Sessions are only renewed once they've been used for this long since they last were, so not
+/// every request writes to the database
+const RENEWAL_GRANULARITY_MILLIS: u64 = 60 * 1000;
+/// How often expired sessions are removed
+const SESSION_PURGE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// How long sessions last before they expire
+#[derive(Clone, Copy)]
+pub struct SessionLifetime {
+    /// How long a session lasts without being used
+    pub idle: Duration,
+    /// How long a session lasts however much it's used
+    pub absolute: Duration,
+}
+
+impl SessionLifetime {
+    /// The lifetimes set with `SESSION_IDLE_SECONDS` and `SESSION_ABSOLUTE_SECONDS` at build time
+    pub fn configured() -> Self {
+        let seconds = |setting: Option<&str>, default: Duration| {
+            setting
+                .and_then(|seconds| seconds.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(default)
+        };
+
+        Self {
+            idle: seconds(crate::SESSION_IDLE_SECONDS, DEFAULT_IDLE_LIFETIME),
+            absolute: seconds(crate::SESSION_ABSOLUTE_SECONDS, DEFAULT_ABSOLUTE_LIFETIME),
+        }
+    }
+
+    /// When a session created at `created_at` expires if it's used at `now`, in milliseconds
+    pub fn expires_at(&self, created_at: u64, now: u64) -> u64 {
+        let idle_expiry = now + self.idle.as_millis() as u64;
+        let absolute_expiry = created_at + self.absolute.as_millis() as u64;
+
+        idle_expiry.min(absolute_expiry)
+    }
+}
 
 pub struct Session {
     pub id: String,
     pub user_id: Option<i32>,
+    /// When the session was created, in milliseconds
+    pub created_at: u64,
+    /// When the session expires unless it's renewed, in milliseconds
+    pub expires_at: u64,
 }
 
 impl Session {
-    pub fn new(id: String, user_id: Option<i32>) -> Session {
-        Self { id, user_id }
+    pub fn new(id: String, user_id: Option<i32>, created_at: u64, expires_at: u64) -> Session {
+        Self {
+            id,
+            user_id,
+            created_at,
+            expires_at,
+        }
+    }
+
+    /// How long until the session can't be renewed any more, which is as long as a cookie for it
+    /// needs to last
+    pub fn max_age(&self) -> Duration {
+        let absolute_expiry =
+            self.created_at + SessionLifetime::configured().absolute.as_millis() as u64;
+
+        Duration::from_millis(absolute_expiry.saturating_sub(now()))
     }
 }
 
@@ -23,8 +86,10 @@ impl<'stmt> TryFrom<&'stmt Row<'stmt>> for Session {
     fn try_from(row: &Row) -> Result<Self, Self::Error> {
         let id = row.get(0)?;
         let user_id = row.get(1)?;
+        let created_at = row.get(2)?;
+        let expires_at = row.get(3)?;
 
-        Ok(Self::new(id, user_id))
+        Ok(Self::new(id, user_id, created_at, expires_at))
     }
 }
 
@@ -32,37 +97,136 @@ fn generate_session_id() -> String {
     Uuid::new_v4().to_string()
 }
 
-pub fn create_session() -> Result<Session, Error> {
+/// Adds a new session, returning it
+fn insert_session(conn: &Connection, user_id: Option<i32>) -> Result<Session, Error> {
     let session_id = generate_session_id();
-    let expires_at = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards somehow")
-        .as_millis() as u64;
-
-    let conn = Connection::open(DB_PATH)?;
+    let created_at = now();
+    let expires_at = SessionLifetime::configured().expires_at(created_at, created_at);
 
     conn.execute(
         load_query!("insert_session.sql"),
-        named_params! { ":id": session_id, ":expires_at": expires_at },
+        named_params! {
+            ":id": session_id,
+            ":user_id": user_id,
+            ":created_at": created_at,
+            ":expires_at": expires_at,
+        },
+    )?;
+
+    // Session IDs are random, so the newest session can't be found by sorting on them
+    Ok(Session::new(session_id, user_id, created_at, expires_at))
+}
+
+pub fn create_session() -> Result<Session, Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    insert_session(&conn, None)
+}
+
+/// Replaces a session with a new one signed in as the user, or nobody, so the old session ID stops
+/// working
+///
+/// Signing in always changes the ID, so an ID planted in someone's browser before they sign in
+/// can't be used to act as them afterwards
+pub fn rotate_session(session_id: &str, user_id: Option<i32>) -> Result<Session, Error> {
+    let mut conn = Connection::open(DB_PATH)?;
+    let transaction = conn.transaction()?;
+
+    transaction.execute(
+        load_query!("delete_session.sql"),
+        named_params! { ":session_id": session_id },
     )?;
+    let session = insert_session(&transaction, user_id)?;
 
-    // Get the created user
-    let mut statement = conn.prepare(load_query!("select_last_session.sql"))?;
-    let session = statement.query_row(params![], |row| row.try_into());
+    transaction.commit()?;
 
-    session
+    Ok(session)
 }
 
+/// Looks up a session, unless it has expired
 pub fn retrieve_session(id: &str) -> Result<Option<Session>, Error> {
     let conn = Connection::open(DB_PATH)?;
 
     // Get the created session
     let mut statement = conn.prepare(load_query!("select_session.sql"))?;
-    let session = statement
-        .query_row(named_params! { ":session_id": id }, |row| row.try_into())
-        .optional();
+    statement
+        .query_row(named_params! { ":session_id": id, ":now": now() }, |row| {
+            row.try_into()
+        })
+        .optional()
+}
+
+/// Pushes back when a session which was just used expires, up to its absolute lifetime
+pub fn renew_session(session: &mut Session) -> Result<(), Error> {
+    let expires_at = SessionLifetime::configured().expires_at(session.created_at, now());
+
+    if expires_at < session.expires_at + RENEWAL_GRANULARITY_MILLIS {
+        return Ok(());
+    }
+
+    let conn = Connection::open(DB_PATH)?;
 
-    session
+    conn.execute(
+        load_query!("update_session_expiry.sql"),
+        named_params! { ":session_id": session.id, ":expires_at": expires_at },
+    )?;
+
+    session.expires_at = expires_at;
+
+    Ok(())
+}
+
+/// Removes every session which has expired, returning how many there were
+pub fn delete_expired_sessions() -> Result<usize, Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    conn.execute(
+        load_query!("delete_expired_sessions.sql"),
+        named_params! { ":now": now() },
+    )
+}
+
+/// Regularly removes sessions which have expired, which would otherwise be kept forever
+pub async fn run_session_purge() {
+    let mut interval = tokio::time::interval(SESSION_PURGE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        match delete_expired_sessions() {
+            Ok(0) => {}
+            Ok(purged) => println!("Purged {} expired sessions", purged),
+            Err(e) => eprintln!("Could not purge expired sessions: {}", e),
+        }
+    }
+}
+
+/// Makes a session expire straight away, which otherwise takes at least a minute
+#[cfg(test)]
+pub fn expire_session(session_id: &str) -> Result<(), Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    conn.execute(
+        load_query!("update_session_expiry.sql"),
+        named_params! { ":session_id": session_id, ":expires_at": now() - 1 },
+    )?;
+
+    Ok(())
+}
+
+/// Whether a session is still in the database, even if it's expired
+#[cfg(test)]
+pub fn session_exists(session_id: &str) -> Result<bool, Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    let mut statement = conn.prepare(load_query!("select_session.sql"))?;
+    statement
+        .query_row(
+            named_params! { ":session_id": session_id, ":now": 0 },
+            |row| Session::try_from(row),
+        )
+        .optional()
+        .map(|session| session.is_some())
 }
 
 pub fn set_session_user(session_id: &str, user_id: i32) -> Result<Session, Error> {
@@ -74,9 +238,8 @@ pub fn set_session_user(session_id: &str, user_id: i32) -> Result<Session, Error
     )?;
 
     let mut statement = conn.prepare(load_query!("select_session.sql"))?;
-    let session = statement.query_row(named_params! {":session_id": session_id}, |row| {
-        row.try_into()
-    });
-
-    session
+    statement.query_row(
+        named_params! {":session_id": session_id, ":now": now()},
+        |row| row.try_into(),
+    )
 }
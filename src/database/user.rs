@@ -1,44 +1,317 @@
+use async_graphql::SimpleObject;
 use macros::load_query;
-use rusqlite::{named_params, params, Connection, Error, OptionalExtension, Result};
+use rusqlite::{
+    named_params, params, Connection, Error, OptionalExtension, Result, Row, TransactionBehavior,
+};
+use serde::Serialize;
+use utoipa::ToSchema;
 
 use super::constants::DB_PATH;
+use crate::user::names::{name_key, name_skeleton};
 
+#[derive(Clone, Serialize, SimpleObject, ToSchema)]
 pub struct User {
     pub id: i32,
     pub name: String,
+    /// Kept out of the API and GraphQL, so admins can't be picked out to target
+    #[serde(skip)]
+    #[graphql(skip)]
+    pub is_admin: bool,
+    /// What the user would rather be called, shown alongside their name
+    pub display_name: Option<String>,
+    pub pronouns: Option<String>,
+    pub bio: Option<String>,
+    /// Where the user's avatar is, which is an identicon unless they've uploaded one
+    pub avatar_url: String,
 }
 
 impl User {
-    pub fn new(id: i32, name: String) -> Self {
-        Self { id, name }
+    pub fn new(id: i32, name: String, is_admin: bool) -> Self {
+        Self {
+            id,
+            name,
+            is_admin,
+            display_name: None,
+            pronouns: None,
+            bio: None,
+            avatar_url: avatar_url(id, None),
+        }
+    }
+
+    /// What to call the user, which is their display name if they've set one
+    pub fn shown_name(&self) -> &str {
+        self.display_name.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// Where a user's avatar is, changing whenever a new one is uploaded so it can be cached forever
+fn avatar_url(id: i32, avatar_updated_at: Option<u64>) -> String {
+    match avatar_updated_at {
+        Some(updated_at) => format!("/user/{id}/avatar/?v={updated_at}"),
+        None => format!("/user/{id}/avatar/"),
+    }
+}
+
+impl<'stmt> TryFrom<&'stmt Row<'stmt>> for User {
+    type Error = Error;
+
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        let id = row.get(0)?;
+        let name = row.get(1)?;
+        let is_admin = row.get(2)?;
+
+        Ok(Self {
+            display_name: row.get(3)?,
+            pronouns: row.get(4)?,
+            bio: row.get(5)?,
+            avatar_url: avatar_url(id, row.get(6)?),
+            ..Self::new(id, name, is_admin)
+        })
     }
 }
 
+/// Creates a user who can't sign in, i.e. for a hook to post as
+///
+/// Fails if the name is already taken
 pub fn create_user(name: &str) -> Result<User, Error> {
     let conn = Connection::open(DB_PATH)?;
 
     conn.execute(
         load_query!("insert_user.sql"),
-        named_params! { ":user_name": name},
+        named_params! {
+            ":user_name": name,
+            ":name_key": name_key(name),
+            ":name_skeleton": name_skeleton(name),
+        },
     )?;
 
     // Get the created user
     let mut statement = conn.prepare(load_query!("select_last_user.sql"))?;
 
-    let user = statement.query_row(params![], |row| Ok(User::new(row.get(0)?, row.get(1)?)));
+    statement.query_row(params![], |row| row.try_into())
+}
+
+/// Finds the user with a name, ignoring case and differences in how it was typed
+pub fn retrieve_user_by_name(name: &str) -> Result<Option<User>, Error> {
+    let conn = Connection::open(DB_PATH)?;
 
-    user
+    let mut statement = conn.prepare(load_query!("select_user_by_name.sql"))?;
+
+    statement
+        .query_row(named_params! {":name_key": name_key(name)}, |row| {
+            row.try_into()
+        })
+        .optional()
+}
+
+/// Finds a user whose name looks like the name, even if it's spelt differently
+pub fn retrieve_user_by_skeleton(name: &str) -> Result<Option<User>, Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    let mut statement = conn.prepare(load_query!("select_user_by_skeleton.sql"))?;
+
+    statement
+        .query_row(
+            named_params! {":name_skeleton": name_skeleton(name)},
+            |row| row.try_into(),
+        )
+        .optional()
 }
 
 pub fn retrieve_user(id: i32) -> Result<Option<User>, Error> {
     let conn = Connection::open(DB_PATH)?;
 
     let mut statement = conn.prepare(load_query!("select_user.sql"))?;
-    let user = statement
-        .query_row(named_params! {":id": id}, |row| {
-            Ok(User::new(row.get(0)?, row.get(1)?))
+
+    statement
+        .query_row(named_params! {":id": id}, |row| row.try_into())
+        .optional()
+}
+
+/// Creates a user who signs in with a password, or nothing if someone else's name looks like theirs
+///
+/// Only keys are unique in the database, so lookalikes are checked in the same transaction as the
+/// user is created in
+pub fn create_user_with_password(name: &str, password_hash: &str) -> Result<Option<User>, Error> {
+    let mut conn = Connection::open(DB_PATH)?;
+    let transaction = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+    let key = name_key(name);
+    let skeleton = name_skeleton(name);
+
+    let lookalike: Option<User> = transaction
+        .query_row(
+            load_query!("select_user_by_skeleton.sql"),
+            named_params! {":name_skeleton": skeleton},
+            |row| row.try_into(),
+        )
+        .optional()?;
+
+    // Someone with the same name is refused by the key instead, as the name is taken
+    if lookalike.is_some_and(|user| name_key(&user.name) != key) {
+        return Ok(None);
+    }
+
+    transaction.execute(
+        load_query!("insert_user_with_password.sql"),
+        named_params! {
+            ":user_name": name,
+            ":name_key": key,
+            ":name_skeleton": skeleton,
+            ":password_hash": password_hash,
+        },
+    )?;
+
+    // Get the created user
+    let user = transaction.query_row(load_query!("select_last_user.sql"), params![], |row| {
+        row.try_into()
+    })?;
+
+    transaction.commit()?;
+
+    Ok(Some(user))
+}
+
+/// Finds the user with a name along with their password hash, which is missing if they've never
+/// set a password
+pub fn retrieve_user_credentials(name: &str) -> Result<Option<(User, Option<String>)>, Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    let mut statement = conn.prepare(load_query!("select_user_credentials.sql"))?;
+
+    statement
+        .query_row(named_params! {":name_key": name_key(name)}, |row| {
+            Ok((row.try_into()?, row.get(7)?))
         })
-        .optional();
+        .optional()
+}
+
+/// Sets the password of a user who doesn't have one yet, returning whether it was set
+pub fn claim_user(id: i32, password_hash: &str) -> Result<bool, Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    let updated = conn.execute(
+        load_query!("update_user_password_if_unclaimed.sql"),
+        named_params! {
+            ":id": id,
+            ":password_hash": password_hash,
+        },
+    )?;
+
+    Ok(updated == 1)
+}
+
+/// Makes a user an admin, or not, which is otherwise done by hand in the database
+#[cfg(test)]
+pub fn set_admin(id: i32, is_admin: bool) -> Result<(), Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    conn.execute(
+        load_query!("update_user_admin.sql"),
+        named_params! { ":id": id, ":is_admin": is_admin },
+    )?;
+
+    Ok(())
+}
+
+/// Gives users from before names were normalised their name's key and skeleton
+///
+/// Where several users share a name, whoever has claimed it keeps it, and otherwise whoever used
+/// it first. Everyone else is left without a key, so can't be found by name
+pub fn backfill_name_keys() -> Result<(), Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    let mut statement = conn.prepare(load_query!("select_users_without_name_key.sql"))?;
+    let users = statement
+        .query_map(params![], |row| {
+            Ok((row.get::<_, i32>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for (id, name) in users {
+        conn.execute(
+            load_query!("update_user_name_key.sql"),
+            named_params! {
+                ":id": id,
+                ":name_key": name_key(&name),
+                ":name_skeleton": name_skeleton(&name),
+            },
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Changes what a user says about themselves
+pub fn update_user_profile(
+    id: i32,
+    display_name: Option<&str>,
+    pronouns: Option<&str>,
+    bio: Option<&str>,
+) -> Result<(), Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    conn.execute(
+        load_query!("update_user_profile.sql"),
+        named_params! {
+            ":id": id,
+            ":display_name": display_name,
+            ":pronouns": pronouns,
+            ":bio": bio,
+        },
+    )?;
+
+    Ok(())
+}
+
+/// An uploaded avatar
+pub struct Avatar {
+    pub content_type: String,
+    pub image: Vec<u8>,
+}
+
+/// Sets a user's avatar, replacing any they had
+pub fn set_avatar(user_id: i32, avatar: &Avatar, updated_at: u64) -> Result<(), Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    conn.execute(
+        load_query!("upsert_avatar.sql"),
+        named_params! {
+            ":user_id": user_id,
+            ":content_type": avatar.content_type,
+            ":image": avatar.image,
+            ":updated_at": updated_at,
+        },
+    )?;
+
+    Ok(())
+}
+
+/// The avatar a user uploaded, if they have
+pub fn get_avatar(user_id: i32) -> Result<Option<Avatar>, Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    conn.query_row(
+        load_query!("select_avatar.sql"),
+        named_params! { ":user_id": user_id },
+        |row| {
+            Ok(Avatar {
+                content_type: row.get(0)?,
+                image: row.get(1)?,
+            })
+        },
+    )
+    .optional()
+}
+
+/// Removes a user's avatar, so they're shown an identicon again
+pub fn delete_avatar(user_id: i32) -> Result<(), Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    conn.execute(
+        load_query!("delete_avatar.sql"),
+        named_params! { ":user_id": user_id },
+    )?;
 
-    user
+    Ok(())
 }
@@ -0,0 +1,210 @@
+use macros::load_query;
+use rusqlite::{named_params, params, Connection, Error, Result, Row};
+
+use super::constants::DB_PATH;
+
+pub struct Webhook {
+    pub id: i32,
+    pub url: String,
+    pub secret: String,
+}
+
+impl Webhook {
+    pub fn new(id: i32, url: String, secret: String) -> Self {
+        Self { id, url, secret }
+    }
+
+    /// The secret with all but its last few characters hidden, for showing after it's created
+    pub fn masked_secret(&self) -> String {
+        let shown = self.secret.chars().count().saturating_sub(4);
+        let end: String = self.secret.chars().skip(shown).collect();
+
+        format!("••••{end}")
+    }
+}
+
+impl<'stmt> TryFrom<&'stmt Row<'stmt>> for Webhook {
+    type Error = Error;
+
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        let id = row.get(0)?;
+        let url = row.get(1)?;
+        let secret = row.get(2)?;
+
+        Ok(Self::new(id, url, secret))
+    }
+}
+
+/// Where a webhook delivery is up to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeliveryStatus {
+    /// Waiting to be (re)tried by the delivery worker
+    Pending,
+    /// The receiver responded with a 2xx status
+    Delivered,
+    /// Every attempt failed and no more will be made
+    Failed,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Delivered => "delivered",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+/// A single event queued to be sent to a webhook, along with the webhook's details
+pub struct WebhookDelivery {
+    pub id: i32,
+    pub url: String,
+    pub secret: String,
+    pub event: String,
+    pub payload: String,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    pub last_status_code: Option<u16>,
+    pub last_error: Option<String>,
+}
+
+impl<'stmt> TryFrom<&'stmt Row<'stmt>> for WebhookDelivery {
+    type Error = Error;
+
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        let status: String = row.get(5)?;
+
+        let status = match status.as_str() {
+            "delivered" => DeliveryStatus::Delivered,
+            "failed" => DeliveryStatus::Failed,
+            _ => DeliveryStatus::Pending,
+        };
+
+        Ok(Self {
+            id: row.get(0)?,
+            url: row.get(1)?,
+            secret: row.get(2)?,
+            event: row.get(3)?,
+            payload: row.get(4)?,
+            status,
+            attempts: row.get(6)?,
+            last_status_code: row.get(7)?,
+            last_error: row.get(8)?,
+        })
+    }
+}
+
+/// Registers a new endpoint to receive chat events
+pub fn create_webhook(url: &str, secret: &str, created_at: u64) -> Result<Webhook, Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    conn.execute(
+        load_query!("insert_webhook.sql"),
+        named_params! { ":url": url, ":secret": secret, ":created_at": created_at },
+    )?;
+
+    // Get the created webhook
+    let mut statement = conn.prepare(load_query!("select_last_webhook.sql"))?;
+
+    statement.query_row(params![], |row| row.try_into())
+}
+
+/// Retrieves all registered webhooks
+pub fn get_webhooks() -> Result<Vec<Webhook>, Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    let mut statement = conn.prepare(load_query!("select_all_webhooks.sql"))?;
+    let webhooks = statement
+        .query_map(params![], |row| row.try_into())?
+        .collect::<Result<Vec<Webhook>, Error>>()?;
+
+    Ok(webhooks)
+}
+
+/// Removes a webhook, along with its delivery log
+pub fn delete_webhook(webhook_id: i32) -> Result<usize, Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    conn.execute(
+        load_query!("delete_webhook.sql"),
+        named_params! { ":webhook_id": webhook_id },
+    )
+}
+
+/// Queues an event to be sent to a webhook as soon as the delivery worker picks it up
+pub fn create_delivery(webhook_id: i32, event: &str, payload: &str, now: u64) -> Result<(), Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    conn.execute(
+        load_query!("insert_webhook_delivery.sql"),
+        named_params! {
+            ":webhook_id": webhook_id,
+            ":event": event,
+            ":payload": payload,
+            ":now": now,
+        },
+    )?;
+
+    Ok(())
+}
+
+/// Retrieves all pending deliveries which are due to be attempted
+pub fn get_pending_deliveries(now: u64) -> Result<Vec<WebhookDelivery>, Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    let mut statement = conn.prepare(load_query!("select_pending_webhook_deliveries.sql"))?;
+    let deliveries = statement
+        .query_map(named_params! { ":now": now }, |row| row.try_into())?
+        .collect::<Result<Vec<WebhookDelivery>, Error>>()?;
+
+    Ok(deliveries)
+}
+
+/// Takes a due delivery for the caller to attempt, by pushing its next attempt back until
+/// `lease_until`, so no other worker attempts it meanwhile
+///
+/// Returns whether the delivery was claimed, which it won't be if another worker got to it first
+pub fn claim_delivery(delivery_id: i32, now: u64, lease_until: u64) -> Result<bool, Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    let claimed = conn.execute(
+        load_query!("claim_webhook_delivery.sql"),
+        named_params! {
+            ":delivery_id": delivery_id,
+            ":now": now,
+            ":lease_until": lease_until,
+        },
+    )?;
+
+    Ok(claimed == 1)
+}
+
+/// Retrieves the most recent deliveries, newest first
+pub fn get_recent_deliveries(limit: u32) -> Result<Vec<WebhookDelivery>, Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    let mut statement = conn.prepare(load_query!("select_recent_webhook_deliveries.sql"))?;
+    let deliveries = statement
+        .query_map(named_params! { ":limit": limit }, |row| row.try_into())?
+        .collect::<Result<Vec<WebhookDelivery>, Error>>()?;
+
+    Ok(deliveries)
+}
+
+/// Records the outcome of a delivery attempt
+pub fn update_delivery(delivery: &WebhookDelivery, next_attempt_at: u64) -> Result<usize, Error> {
+    let conn = Connection::open(DB_PATH)?;
+
+    conn.execute(
+        load_query!("update_webhook_delivery.sql"),
+        named_params! {
+            ":delivery_id": delivery.id,
+            ":status": delivery.status.as_str(),
+            ":attempts": delivery.attempts,
+            ":next_attempt_at": next_attempt_at,
+            ":last_status_code": delivery.last_status_code,
+            ":last_error": delivery.last_error,
+        },
+    )
+}
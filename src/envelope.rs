@@ -0,0 +1,84 @@
+use serde::Serialize;
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::time::now;
+
+/// The version of the envelope format, which only changes when clients would need changing too
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// What a websocket wants sent to it, negotiated with its `Sec-WebSocket-Protocol` header
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Protocol {
+    /// HTML fragments for htmx to swap in
+    Html,
+    /// JSON for any other client
+    Json,
+}
+
+impl Protocol {
+    /// Every supported protocol, in order of preference
+    pub const ALL: [Protocol; 2] = [Protocol::Html, Protocol::Json];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Html => "jdp-chat.v1.html",
+            Self::Json => "jdp-chat.v1.json",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|protocol| protocol.name() == name)
+    }
+}
+
+/// What a frame carries, in whichever form the websocket's protocol asks for
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Payload {
+    Html(String),
+    Data(Value),
+}
+
+/// Every frame sent over websockets, i.e.
+/// `{"version": 1, "type": "message.created", "id": "…", "timestamp": 1726000000000, "html": "…"}`
+#[derive(Serialize)]
+pub struct Envelope<'a> {
+    version: u32,
+    #[serde(rename = "type")]
+    kind: &'a str,
+    /// Shared by every websocket's copy of the same broadcast
+    id: &'a str,
+    /// When the frame was sent, in milliseconds
+    timestamp: u64,
+    #[serde(flatten)]
+    payload: &'a Payload,
+}
+
+impl<'a> Envelope<'a> {
+    pub fn new(kind: &'a str, id: &'a str, timestamp: u64, payload: &'a Payload) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            kind,
+            id,
+            timestamp,
+            payload,
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).expect("Envelopes can always be serialised")
+    }
+}
+
+/// A new ID for a frame, or a broadcast of one
+pub fn frame_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// Encodes a frame sent to a single websocket
+pub fn frame(kind: &str, payload: &Payload) -> String {
+    Envelope::new(kind, &frame_id(), now(), payload).encode()
+}
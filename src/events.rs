@@ -0,0 +1,149 @@
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, Receiver, Sender};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::database::message::Message;
+
+/// How many events are kept for subscribers which fall behind before they start missing out
+const EVENT_BUS_CAPACITY: usize = 1024;
+
+/// Something which happened in the chat
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(tag = "type", content = "data")]
+pub enum Event {
+    MessageCreated(Message),
+    MessageDeleted(Message),
+    UserLoggedIn {
+        user_id: i32,
+        session_id: String,
+        /// The session which was replaced by signing in, so live connections made with it can
+        /// follow along
+        previous_session_id: Option<String>,
+    },
+    UserLoggedOut {
+        user_id: i32,
+        session_id: String,
+        /// The session which was replaced by signing out
+        previous_session_id: String,
+    },
+    SessionCreated,
+    /// Someone is writing a message
+    UserTyping {
+        user_id: i32,
+        name: String,
+    },
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::MessageCreated(_) => "message.created",
+            Self::MessageDeleted(_) => "message.deleted",
+            Self::UserLoggedIn { .. } => "user.logged_in",
+            Self::UserLoggedOut { .. } => "user.logged_out",
+            Self::SessionCreated => "session.created",
+            Self::UserTyping { .. } => "user.typing",
+        }
+    }
+}
+
+/// Publishes events from the views to anything which wants to react to them, i.e. the websocket
+/// broadcast or webhooks, so the views don't need to know about them
+///
+/// Events from other instances sharing the database are published here too, but only to
+/// subscribers of every event, so anything with side effects only happens once
+#[derive(Clone)]
+pub struct EventBus {
+    sender: Sender<Event>,
+    local: Sender<Event>,
+    /// Queues of this instance's events for subscribers which can't miss any
+    queues: Arc<Mutex<Vec<UnboundedSender<Event>>>>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUS_CAPACITY);
+        let (local, _) = broadcast::channel(EVENT_BUS_CAPACITY);
+
+        Self {
+            sender,
+            local,
+            queues: Arc::default(),
+        }
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn publish(&self, event: Event) {
+        // Queues whose receiver has gone are dropped
+        self.queues
+            .lock()
+            .unwrap()
+            .retain(|queue| queue.send(event.clone()).is_ok());
+
+        // Sending only fails when nothing is subscribed, in which case nobody cares about the event
+        let _ = self.local.send(event.clone());
+        let _ = self.sender.send(event);
+    }
+
+    /// Publishes an event which happened on another instance
+    pub fn publish_remote(&self, event: Event) {
+        let _ = self.sender.send(event);
+    }
+
+    /// Subscribes to every event, wherever it happened, i.e. to push them to live clients
+    pub fn subscribe(&self) -> Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    /// Subscribes to only the events which happened on this instance
+    pub fn subscribe_local(&self) -> Receiver<Event> {
+        self.local.subscribe()
+    }
+
+    /// Queues every event which happens on this instance from now on, i.e. to share them with
+    /// other instances
+    ///
+    /// Unlike subscribers, queues never drop events however far behind their receiver falls
+    pub fn queue_local(&self) -> UnboundedReceiver<Event> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.queues.lock().unwrap().push(sender);
+
+        receiver
+    }
+}
+
+/// Logs every event as it happens
+pub async fn run_audit_log(mut receiver: Receiver<Event>) {
+    loop {
+        let event = match receiver.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(count)) => {
+                eprintln!("Audit log missed {} events", count);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+
+        match &event {
+            Event::MessageCreated(message) | Event::MessageDeleted(message) => println!(
+                "[audit] {} id={} author_id={}",
+                event.name(),
+                message.id,
+                message.author_id
+            ),
+            Event::UserLoggedIn { user_id, .. } | Event::UserLoggedOut { user_id, .. } => {
+                println!("[audit] {} user_id={}", event.name(), user_id)
+            }
+            Event::SessionCreated => println!("[audit] {}", event.name()),
+            // Typing happens far too often to be worth recording
+            Event::UserTyping { .. } => {}
+        }
+    }
+}
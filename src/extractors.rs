@@ -1,10 +1,29 @@
 use axum::async_trait;
-use axum::extract::FromRequestParts;
+use axum::extract::{FromRef, FromRequestParts};
 use axum::http::request::Parts;
 use axum::http::StatusCode;
+use axum_extra::extract::cookie::Cookie;
 use axum_extra::extract::CookieJar;
 
-use crate::database::session::{create_session, retrieve_session, Session};
+use crate::database::session::{create_session, renew_session, retrieve_session, Session};
+use crate::events::{Event, EventBus};
+use crate::websocket::is_allowed_origin;
+
+/// Says whether a browser's request came from a page on the same site, another site, or neither
+const SEC_FETCH_SITE_HEADER: &str = "Sec-Fetch-Site";
+
+/// The cookie which keeps a browser on a session, for as long as the session could be renewed
+///
+/// Sessions which go unused expire sooner, which is only known to the server
+pub fn session_cookie(session: &Session) -> Cookie<'static> {
+    let mut cookie = Cookie::build(("session_id", session.id.clone())).secure(true);
+
+    if let Ok(max_age) = session.max_age().try_into() {
+        cookie = cookie.max_age(max_age);
+    }
+
+    cookie.build()
+}
 
 /// Pulls the current session out of the custom session_id HTTP header
 pub struct ExtractSession(pub Session);
@@ -13,6 +32,7 @@ pub struct ExtractSession(pub Session);
 impl<S> FromRequestParts<S> for ExtractSession
 where
     S: Send + Sync,
+    EventBus: FromRef<S>,
 {
     type Rejection = (StatusCode, &'static str);
 
@@ -31,10 +51,8 @@ where
         let mut session = None;
 
         if let Some(session_cookie) = jar.get("session_id") {
-            println!("{:?}", session_cookie);
             // Try and load an existing session
             let session_id = session_cookie.value();
-            println!("{session_id}");
 
             if let Ok(session_lookup) = retrieve_session(session_id) {
                 // The database lookup succeeded, set the result of an existing session or not
@@ -42,12 +60,10 @@ where
             }
         }
 
-        println!("Sessssion exists: {}", session.is_some());
-
         if session.is_none() {
             // If the request has no session, generate one
             if let Ok(new_session) = create_session() {
-                println!("Created new session: {}", new_session.id);
+                EventBus::from_ref(state).publish(Event::SessionCreated);
                 return Ok(ExtractSession(new_session));
             } else {
                 return Err((
@@ -57,6 +73,40 @@ where
             }
         }
 
-        Ok(ExtractSession(session.unwrap()))
+        let mut session = session.unwrap();
+
+        // Sessions in use are kept alive, up to their absolute lifetime
+        if let Err(e) = renew_session(&mut session) {
+            eprintln!("Failed to renew session: {}", e);
+        }
+
+        Ok(ExtractSession(session))
+    }
+}
+
+/// Rejects requests made from other sites' pages, which browsers send the session cookie along with
+///
+/// Browsers say where a request came from with `Sec-Fetch-Site`, or failing that `Origin`. Clients
+/// which send neither aren't browsers, so can't have been tricked into making the request
+pub struct SameOrigin;
+
+#[async_trait]
+impl<S> FromRequestParts<S> for SameOrigin
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let same_origin = match parts.headers.get(SEC_FETCH_SITE_HEADER) {
+            // Typed into the address bar or opened from a bookmark, rather than sent by a page
+            Some(site) => site == "same-origin" || site == "none",
+            None => is_allowed_origin(&parts.headers, None),
+        };
+
+        match same_origin {
+            true => Ok(SameOrigin),
+            false => Err((StatusCode::FORBIDDEN, "Cross-site requests aren't allowed")),
+        }
     }
 }
@@ -0,0 +1,110 @@
+use std::time::Duration;
+
+use rusqlite::Error;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::task::spawn_blocking;
+use uuid::Uuid;
+
+use crate::database::event::{
+    create_event, delete_events_before, get_events_after, get_last_event_id,
+};
+use crate::events::{Event, EventBus};
+use crate::time::now;
+
+/// How often the event table is checked for events from other instances
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// How long events are kept for, in milliseconds
+const EVENT_RETENTION_MILLIS: u64 = 5 * 60 * 1000;
+/// How often old events are removed
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Runs database work on the blocking thread pool, so polling never holds up other tasks
+async fn blocking<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> T {
+    spawn_blocking(work)
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
+
+/// Shares events between every instance running against the same database, so clients are sent
+/// everything no matter which instance they're connected to
+///
+/// Each instance records its own events in the `event` table, and tails it for everyone else's.
+/// SQLite only has one writer at a time, so events become visible in the order of their IDs and
+/// none are skipped by remembering the last one seen. This instance's events are queued for
+/// recording rather than subscribed to, so none are dropped if recording falls behind
+pub struct Fanout {
+    events: EventBus,
+    instance_id: String,
+    last_event_id: i32,
+}
+
+impl Fanout {
+    /// Starts from the newest event, as anything older happened before this instance was around
+    pub fn new(events: EventBus) -> Result<Self, Error> {
+        Ok(Self {
+            events,
+            instance_id: Uuid::new_v4().to_string(),
+            last_event_id: get_last_event_id()?,
+        })
+    }
+
+    async fn record(&self, event: Event) -> Result<(), Error> {
+        // Only webhooks and the audit log care about new sessions, and they only need them once
+        if let Event::SessionCreated = event {
+            return Ok(());
+        }
+
+        let payload = serde_json::to_string(&event).expect("Events can always be serialised");
+        let instance_id = self.instance_id.clone();
+
+        blocking(move || create_event(&instance_id, event.name(), &payload, now())).await
+    }
+
+    /// Publishes every event other instances have recorded since the last poll
+    async fn poll(&mut self) -> Result<(), Error> {
+        let after = self.last_event_id;
+        let instance_id = self.instance_id.clone();
+
+        for stored in blocking(move || get_events_after(after, &instance_id)).await? {
+            self.last_event_id = stored.id;
+
+            match serde_json::from_str(&stored.payload) {
+                Ok(event) => self.events.publish_remote(event),
+                Err(e) => eprintln!("Could not read event {}: {}", stored.id, e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Records events published on this instance, and publishes those from other instances
+    pub async fn run(mut self, mut receiver: UnboundedReceiver<Event>) {
+        let mut poll_interval = tokio::time::interval(POLL_INTERVAL);
+        let mut prune_interval = tokio::time::interval(PRUNE_INTERVAL);
+
+        loop {
+            tokio::select! {
+                received = receiver.recv() => match received {
+                    Some(event) => {
+                        if let Err(e) = self.record(event).await {
+                            eprintln!("Could not record event: {}", e);
+                        }
+                    }
+                    None => return,
+                },
+                _ = poll_interval.tick() => {
+                    if let Err(e) = self.poll().await {
+                        eprintln!("Could not read events from other instances: {}", e);
+                    }
+                }
+                _ = prune_interval.tick() => {
+                    let before = now().saturating_sub(EVENT_RETENTION_MILLIS);
+
+                    if let Err(e) = blocking(move || delete_events_before(before)).await {
+                        eprintln!("Could not remove old events: {}", e);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::{self, Receiver, Sender};
+
+use crate::events::Event;
+use crate::render_event;
+
+/// How many fragments are kept so clients which reconnect can catch up
+const HISTORY_CAPACITY: usize = 256;
+
+/// The HTML for an event, numbered so clients can say which fragments they've already seen
+#[derive(Clone, Serialize)]
+pub struct Fragment {
+    pub id: u64,
+    pub html: String,
+}
+
+/// What a client which reconnects has missed since the last fragment it saw
+pub enum Missed {
+    /// Every fragment it missed, oldest first
+    Fragments(Vec<Fragment>),
+    /// More than is remembered, so it has to load the messages again, then carry on after
+    /// `newest_id`
+    Everything { newest_id: u64 },
+}
+
+/// Renders every chat event into the HTML fragment pushed to live clients, keeping the most recent
+/// ones around so they can be sent again to clients which missed them
+#[derive(Clone)]
+pub struct FragmentLog {
+    sender: Sender<Fragment>,
+    history: Arc<Mutex<VecDeque<Fragment>>>,
+}
+
+impl Default for FragmentLog {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(HISTORY_CAPACITY);
+
+        Self {
+            sender,
+            history: Arc::new(Mutex::new(VecDeque::with_capacity(HISTORY_CAPACITY))),
+        }
+    }
+}
+
+impl FragmentLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push(&self, html: String) {
+        let mut history = self.history.lock().unwrap();
+
+        let id = history.back().map_or(1, |fragment| fragment.id + 1);
+        let fragment = Fragment { id, html };
+
+        if history.len() == HISTORY_CAPACITY {
+            history.pop_front();
+        }
+
+        history.push_back(fragment.clone());
+
+        // Sent while the history is locked, so subscribers never see fragments out of order
+        let _ = self.sender.send(fragment);
+    }
+
+    /// The ID of the newest fragment, or 0 if there are none yet
+    pub fn newest_id(&self) -> u64 {
+        self.history
+            .lock()
+            .unwrap()
+            .back()
+            .map_or(0, |fragment| fragment.id)
+    }
+
+    /// Subscribes to new fragments, along with every fragment newer than `last_id`
+    ///
+    /// Nothing is missed or repeated between the two. When fragments the client missed have
+    /// already been forgotten, it's told to start again instead
+    pub fn subscribe(&self, last_id: Option<u64>) -> (Missed, Receiver<Fragment>) {
+        let history = self.history.lock().unwrap();
+
+        let newest_id = history.back().map_or(0, |fragment| fragment.id);
+        let oldest_id = history.front().map_or(1, |fragment| fragment.id);
+
+        let missed = match last_id {
+            // Numbering starts again when the server restarts, so the client can't know what it
+            // missed
+            Some(last_id) if last_id > newest_id => Missed::Everything { newest_id },
+            Some(last_id) if last_id + 1 < oldest_id => Missed::Everything { newest_id },
+            Some(last_id) => Missed::Fragments(
+                history
+                    .iter()
+                    .filter(|fragment| fragment.id > last_id)
+                    .cloned()
+                    .collect(),
+            ),
+            None => Missed::Fragments(vec![]),
+        };
+
+        (missed, self.sender.subscribe())
+    }
+
+    /// Renders events from the event bus as they're published
+    pub async fn run(self, mut receiver: Receiver<Event>) {
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(count)) => {
+                    eprintln!("Fragment log missed {} events", count);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+
+            if let Some(html) = render_event(&event) {
+                self.push(html);
+            }
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use async_graphql::futures_util::stream::{self, Stream};
+use async_graphql::{
+    ComplexObject, Context, Error, ErrorExtensions, Object, Schema, SimpleObject, Subscription,
+};
+use std::net::SocketAddr;
+
+use async_graphql::http::ALL_WEBSOCKET_PROTOCOLS;
+use async_graphql_axum::{GraphQLProtocol, GraphQLRequest, GraphQLResponse, GraphQLWebSocket};
+use axum::extract::ws::WebSocketUpgrade;
+use axum::extract::{ConnectInfo, Extension, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::Router;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::api::views::{DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+use crate::api::BearerUser;
+use crate::chat::{post_message, remove_message, DeleteMessageError, PostMessageError};
+use crate::database::message::{get_message_by_id, get_messages_page, Message};
+use crate::database::user::{retrieve_user, User};
+use crate::events::{Event, EventBus};
+use crate::hooks::MessageHooks;
+use crate::websocket::{client_address, is_allowed_origin, refuse, WebSocketHandler};
+use crate::{AppState, WEBSOCKET_ALLOWED_ORIGINS};
+
+pub type ChatSchema = Schema<QueryRoot, MutationRoot, SubscriptionRoot>;
+
+/// Builds an error with the same codes as the JSON API, under `extensions.code`
+fn error(code: &'static str, message: impl Into<String>) -> Error {
+    Error::new(message).extend_with(|_, extensions| extensions.set("code", code))
+}
+
+/// The logged in user a request was made by
+fn current_user<'a>(ctx: &Context<'a>) -> Result<&'a User, Error> {
+    ctx.data_opt::<User>()
+        .ok_or_else(|| error("unauthorized", "Not logged in"))
+}
+
+#[ComplexObject]
+impl Message {
+    /// The user who posted the message
+    async fn author(&self) -> Result<Option<User>, Error> {
+        Ok(retrieve_user(self.author_id)?)
+    }
+}
+
+/// Queries for messages and users
+///
+/// There is no `rooms` query, as the app has a single chat and no rooms to query
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// Messages newest first, optionally only those older than `before`
+    async fn messages(
+        &self,
+        before: Option<i32>,
+        limit: Option<u32>,
+    ) -> Result<Vec<Message>, Error> {
+        let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+        Ok(get_messages_page(before, limit)?)
+    }
+
+    async fn message(&self, id: i32) -> Result<Option<Message>, Error> {
+        Ok(get_message_by_id(id)?)
+    }
+
+    async fn user(&self, id: i32) -> Result<Option<User>, Error> {
+        Ok(retrieve_user(id)?)
+    }
+
+    /// The logged in user, or null when not logged in
+    async fn me(&self, ctx: &Context<'_>) -> Option<User> {
+        ctx.data_opt::<User>().cloned()
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct PostMessagePayload {
+    message: Message,
+    /// Messages posted by message hooks in reply to the new message
+    replies: Vec<Message>,
+}
+
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    /// Posts a message as the logged in user
+    async fn post_message(
+        &self,
+        ctx: &Context<'_>,
+        text: String,
+    ) -> Result<PostMessagePayload, Error> {
+        let user = current_user(ctx)?;
+        let message_hooks = ctx.data::<&'static MessageHooks>()?;
+        let events = ctx.data::<EventBus>()?;
+
+        match post_message(&text, user, message_hooks, events).await {
+            Ok((message, replies)) => Ok(PostMessagePayload { message, replies }),
+            Err(PostMessageError::Invalid) => Err(error("invalid_message", "Invalid message")),
+            Err(PostMessageError::Rejected(reason)) => Err(error("rejected", reason)),
+            Err(PostMessageError::Database(e)) => Err(e.into()),
+        }
+    }
+
+    /// Deletes one of the logged in user's messages, returning the deleted message
+    async fn delete_message(&self, ctx: &Context<'_>, id: i32) -> Result<Message, Error> {
+        let user = current_user(ctx)?;
+        let events = ctx.data::<EventBus>()?;
+
+        match remove_message(id, user, events).await {
+            Ok(message) => Ok(message),
+            Err(DeleteMessageError::NotFound) => {
+                Err(error("not_found", format!("Message {id} does not exist")))
+            }
+            Err(DeleteMessageError::Forbidden) => Err(error("forbidden", "Permission denied")),
+            Err(DeleteMessageError::Database(e)) => Err(e.into()),
+        }
+    }
+}
+
+/// Streams the messages picked out of every event published from now on
+fn event_stream(
+    events: &EventBus,
+    select: fn(Event) -> Option<Message>,
+) -> impl Stream<Item = Message> {
+    stream::unfold(events.subscribe(), move |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    if let Some(message) = select(event) {
+                        return Some((message, receiver));
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    eprintln!(
+                        "GraphQL subscription fell behind, skipped {} events",
+                        skipped
+                    );
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    /// Every message posted from now on
+    async fn message_created(
+        &self,
+        ctx: &Context<'_>,
+    ) -> Result<impl Stream<Item = Message>, Error> {
+        let events = ctx.data::<EventBus>()?;
+
+        Ok(event_stream(events, |event| match event {
+            Event::MessageCreated(message) => Some(message),
+            _ => None,
+        }))
+    }
+
+    /// Every message deleted from now on
+    async fn message_deleted(
+        &self,
+        ctx: &Context<'_>,
+    ) -> Result<impl Stream<Item = Message>, Error> {
+        let events = ctx.data::<EventBus>()?;
+
+        Ok(event_stream(events, |event| match event {
+            Event::MessageDeleted(message) => Some(message),
+            _ => None,
+        }))
+    }
+}
+
+pub fn schema(state: &AppState) -> ChatSchema {
+    Schema::build(QueryRoot, MutationRoot, SubscriptionRoot)
+        .data(state.events.clone())
+        .data(state.message_hooks)
+        .finish()
+}
+
+///
+/// POST request to run a GraphQL query or mutation, as the user whose bearer token it has if there
+/// is one
+///
+/// The session cookie isn't used, as any body is parsed as JSON, so other sites could otherwise
+/// post mutations with their visitors' cookies
+///
+async fn graphql_view(
+    Extension(schema): Extension<ChatSchema>,
+    user: Option<BearerUser>,
+    request: GraphQLRequest,
+) -> GraphQLResponse {
+    let mut request = request.into_inner();
+
+    if let Some(BearerUser(user)) = user {
+        request = request.data(user);
+    }
+
+    schema.execute(request).await.into()
+}
+
+///
+/// GET request to upgrade to a websocket for GraphQL subscriptions
+///
+/// Browsers are only accepted from allowed origins, and subscriptions count against the same
+/// limits as the chat's websockets
+///
+async fn graphql_subscription_view(
+    Extension(schema): Extension<ChatSchema>,
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    protocol: GraphQLProtocol,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    if !is_allowed_origin(&headers, WEBSOCKET_ALLOWED_ORIGINS) {
+        return (StatusCode::FORBIDDEN, "Origin not allowed").into_response();
+    }
+
+    let address = client_address(&headers, peer);
+    let upgrade = upgrade.protocols(ALL_WEBSOCKET_PROTOCOLS);
+
+    match WebSocketHandler::add_other_connection(state.websocket_handler, address) {
+        Ok(slot) => upgrade.on_upgrade(move |websocket| async move {
+            GraphQLWebSocket::new(websocket, schema, protocol)
+                .serve()
+                .await;
+
+            drop(slot);
+        }),
+        Err(refused) => upgrade.on_upgrade(move |websocket| refuse(websocket, address, refused)),
+    }
+}
+
+/// The GraphQL endpoint at `/graphql`, with subscriptions served over a websocket at `/graphql/ws`
+pub fn routes(state: &AppState) -> Router<AppState> {
+    let schema = schema(state);
+
+    Router::new()
+        .route("/graphql", post(graphql_view))
+        .route("/graphql/ws", get(graphql_subscription_view))
+        .layer(Extension(schema))
+}
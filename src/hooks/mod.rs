@@ -0,0 +1,99 @@
+use rusqlite::Error;
+
+use crate::database::message::{create_message, Message};
+use crate::database::user::{create_user, retrieve_user_by_name, User};
+use crate::user::names::name_skeleton;
+
+pub mod word_filter;
+
+/// A message which is about to be created, which hooks are free to change
+pub struct MessageDraft {
+    pub text: String,
+    /// Notes shown alongside the message, i.e. "Edited by word filter"
+    pub annotations: Vec<String>,
+}
+
+/// Extension point for processing messages without touching the views
+///
+/// Hooks are registered on `MessageHooks` at startup and run in the order they were registered
+pub trait MessageHook: Send + Sync {
+    /// The name replies from this hook are posted under
+    fn name(&self) -> &str;
+
+    /// Called before a message is created
+    ///
+    /// The draft can be rewritten or annotated. Returning an error rejects the message, and the
+    /// error is shown to its author.
+    fn before_create(&self, _draft: &mut MessageDraft, _author: &User) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Called after a message is created
+    ///
+    /// Returning some text posts it as a reply from this hook
+    fn after_create(&self, _message: &Message, _author: &User) -> Option<String> {
+        None
+    }
+}
+
+/// All hooks which run when a message is created
+#[derive(Default)]
+pub struct MessageHooks {
+    hooks: Vec<Box<dyn MessageHook>>,
+}
+
+impl MessageHooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, hook: impl MessageHook + 'static) {
+        self.hooks.push(Box::new(hook));
+    }
+
+    /// Whether a hook posts under the name, or one which looks like it
+    pub fn has_hook_named(&self, name: &str) -> bool {
+        let skeleton = name_skeleton(name);
+
+        self.hooks
+            .iter()
+            .any(|hook| name_skeleton(hook.name()) == skeleton)
+    }
+
+    /// Runs every hook over a new message, stopping at the first one which rejects it
+    pub fn before_create(&self, text: &str, author: &User) -> Result<MessageDraft, String> {
+        let mut draft = MessageDraft {
+            text: text.to_string(),
+            annotations: Vec::new(),
+        };
+
+        for hook in self.hooks.iter() {
+            hook.before_create(&mut draft, author)?;
+        }
+
+        Ok(draft)
+    }
+
+    /// Runs every hook over a created message, and posts any replies they make
+    ///
+    /// Replies don't run through the hooks themselves, so hooks can't reply to each other forever
+    pub fn after_create(&self, message: &Message, author: &User) -> Result<Vec<Message>, Error> {
+        let mut replies = Vec::new();
+
+        for hook in self.hooks.iter() {
+            let Some(reply) = hook.after_create(message, author) else {
+                continue;
+            };
+
+            // Hooks post under their own user, which is created the first time they reply
+            let bot = match retrieve_user_by_name(hook.name())? {
+                Some(bot) => bot,
+                None => create_user(hook.name())?,
+            };
+
+            replies.push(create_message(&reply, &[], bot.id)?);
+        }
+
+        Ok(replies)
+    }
+}
@@ -0,0 +1,51 @@
+use super::{MessageDraft, MessageHook};
+use crate::database::user::User;
+
+/// Replaces blocked words in messages with asterisks
+pub struct WordFilter {
+    words: Vec<String>,
+}
+
+impl WordFilter {
+    /// Creates a filter from a comma separated list of words, i.e. "heck,darn"
+    pub fn new(words: &str) -> Self {
+        let words = words
+            .split(',')
+            .map(|word| word.trim().to_lowercase())
+            .filter(|word| !word.is_empty())
+            .collect();
+
+        Self { words }
+    }
+}
+
+impl MessageHook for WordFilter {
+    fn name(&self) -> &str {
+        "Word filter"
+    }
+
+    fn before_create(&self, draft: &mut MessageDraft, _author: &User) -> Result<(), String> {
+        let mut filtered = false;
+
+        let text = draft
+            .text
+            .split(' ')
+            .map(|word| {
+                if self.words.contains(&word.to_lowercase()) {
+                    filtered = true;
+                    "*".repeat(word.chars().count())
+                } else {
+                    word.to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        if filtered {
+            draft.text = text;
+            draft.annotations.push("Edited by word filter".to_string());
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+use std::time::Duration;
+
+use axum::extract::{Query, State};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::fragments::{Fragment, FragmentLog, Missed};
+
+/// How long a poll waits for something to happen when the client doesn't say
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(25);
+/// The longest a poll can wait, kept under the timeouts of most proxies
+const MAX_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize)]
+pub struct PollQuery {
+    /// The `last_id` of the previous poll, or nothing to only wait for new fragments
+    after: Option<u64>,
+    /// How many seconds to wait for a new fragment
+    timeout: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct PollResponse {
+    /// Oldest first
+    fragments: Vec<Fragment>,
+    /// Where the next poll carries on from
+    last_id: u64,
+    /// Whether the client missed more than can be sent again, so needs to load the messages again
+    reset: bool,
+}
+
+///
+/// GET request for the same HTML fragments sent over websockets, for clients which can use
+/// neither websockets nor server-sent events
+///
+/// Fragments after `after` are returned straight away. Otherwise the request is held open until
+/// the next fragment arrives, along with any others arriving with it, or the timeout passes
+///
+pub async fn poll_view(
+    State(fragments): State<FragmentLog>,
+    Query(query): Query<PollQuery>,
+) -> Json<PollResponse> {
+    let timeout = query
+        .timeout
+        .map_or(DEFAULT_TIMEOUT, Duration::from_secs)
+        .min(MAX_TIMEOUT);
+
+    let after = query.after.unwrap_or_else(|| fragments.newest_id());
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    loop {
+        let (missed, mut receiver) = fragments.subscribe(Some(after));
+
+        match missed {
+            Missed::Fragments(missed) if !missed.is_empty() => return respond(missed, after),
+            Missed::Fragments(_) => {}
+            Missed::Everything { newest_id } => {
+                return Json(PollResponse {
+                    fragments: vec![],
+                    last_id: newest_id,
+                    reset: true,
+                })
+            }
+        }
+
+        let fragment = match tokio::time::timeout_at(deadline, receiver.recv()).await {
+            Ok(Ok(fragment)) => fragment,
+            // Anything missed is in the history, so start again from there
+            Ok(Err(RecvError::Lagged(_))) => continue,
+            Ok(Err(RecvError::Closed)) | Err(_) => return respond(vec![], after),
+        };
+
+        // Send everything which has already arrived in the same batch
+        let mut batch = vec![fragment];
+        while let Ok(fragment) = receiver.try_recv() {
+            batch.push(fragment);
+        }
+
+        return respond(batch, after);
+    }
+}
+
+fn respond(fragments: Vec<Fragment>, after: u64) -> Json<PollResponse> {
+    let last_id = fragments.last().map_or(after, |fragment| fragment.id);
+
+    Json(PollResponse {
+        fragments,
+        last_id,
+        reset: false,
+    })
+}
@@ -1,51 +1,121 @@
+use std::net::SocketAddr;
 use std::sync::Mutex;
 
 use askama::Template;
-use axum::extract::{Path, State};
-use axum::http::StatusCode;
-use axum::response::IntoResponse;
+use axum::extract::{ConnectInfo, FromRef, Path, State};
+use axum::http::header::{CACHE_CONTROL, ETAG, IF_NONE_MATCH, VARY};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
 use axum::routing::{delete, get, post};
 use axum::Form;
 use axum::Router;
-use axum_extra::extract::cookie::Cookie;
 use axum_extra::extract::CookieJar;
 use serde::Deserialize;
+use serde_json::json;
 use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+use tokio::task::spawn_blocking;
 use tower_http::services::ServeDir;
+use utoipa::{OpenApi, ToSchema};
+use utoipa_swagger_ui::SwaggerUi;
 
+use api::docs::ApiDoc;
+use chat::{post_message, remove_message, DeleteMessageError, PostMessageError};
 use database::message::{
-    can_user_delete, create_message, delete_message, get_message_by_id, get_messages, Message,
+    can_user_delete, get_messages, get_messages_version, mentions_user, Message,
 };
 use database::run_migrations;
-use database::session::set_session_user;
-use database::user::{create_user, retrieve_user};
-use extractors::ExtractSession;
+use database::session::{rotate_session, run_session_purge, Session};
+use database::user::{retrieve_user, User};
+use envelope::{Payload, Protocol};
+use events::{run_audit_log, Event, EventBus};
+use extractors::{session_cookie, ExtractSession};
+use fanout::Fanout;
+use fragments::FragmentLog;
+use hooks::word_filter::WordFilter;
+use hooks::MessageHooks;
+use long_poll::poll_view;
+use script::views::{create_script_view, delete_script_view, scripts_view};
+use script::ScriptHook;
+use sse::events_view;
 use template::HtmlTemplate;
-use user::get_user_from_session;
-use validators::validate_message;
-use websocket::WebSocketHandler;
+use tls::TlsCertificate;
+use user::views::{
+    avatar_view, own_profile_view, profile_card_view, profile_view, update_profile_view,
+};
+use user::{authenticate, get_user_from_session, register_user, AccountError};
+use webhook::run_delivery_worker;
+use webhook::views::{create_webhook_view, delete_webhook_view, webhooks_view};
+use websocket::{client_address, run_reaper, websocket_view, WebSocketHandler};
 
+mod api;
+mod chat;
 mod database;
+mod envelope;
+mod events;
 mod extractors;
+mod fanout;
+mod fragments;
+mod graphql;
+mod hooks;
+mod long_poll;
+mod replay;
+mod script;
+mod sse;
 mod template;
+mod time;
+mod tls;
 mod user;
 mod validators;
+mod webhook;
 mod websocket;
 
 #[cfg(test)]
 mod tests;
 
-const API_ADDRESS: &'static str = env!("API_ADDRESS");
-const WEBSOCKET_ADDRESS: &'static str = env!("WEBSOCKET_ADDRESS");
+const API_ADDRESS: &str = env!("API_ADDRESS");
+/// Where browsers connect to the websocket, an empty string streams server-sent events instead
 const WEBSOCKET_CONNECT_URL: Option<&'static str> = option_env!("WEBSOCKET_CONNECT_URL");
+/// The websocket route on this server, used when no other URL is given
+const DEFAULT_WEBSOCKET_URL: &str = "/ws/";
+/// Comma separated origins, i.e. `https://example.com`, of pages which can connect to the websocket
+/// besides the app's own
+const WEBSOCKET_ALLOWED_ORIGINS: Option<&'static str> = option_env!("WEBSOCKET_ALLOWED_ORIGINS");
+/// The header a proxy in front of the app passes the client's IP address in, i.e. `X-Forwarded-For`
+const CLIENT_IP_HEADER: Option<&'static str> = option_env!("CLIENT_IP_HEADER");
+/// Comma separated words which are censored from messages
+const BLOCKED_WORDS: Option<&'static str> = option_env!("BLOCKED_WORDS");
+/// The PEM certificate chain to serve HTTPS and `wss://` with, along with its key
+const TLS_CERTIFICATE_PATH: Option<&'static str> = option_env!("TLS_CERTIFICATE_PATH");
+/// The PKCS #8 PEM private key for the TLS certificate
+const TLS_KEY_PATH: Option<&'static str> = option_env!("TLS_KEY_PATH");
+/// How many seconds a session lasts without being used, a week unless set
+const SESSION_IDLE_SECONDS: Option<&'static str> = option_env!("SESSION_IDLE_SECONDS");
+/// How many seconds a session lasts however much it's used, 30 days unless set
+const SESSION_ABSOLUTE_SECONDS: Option<&'static str> = option_env!("SESSION_ABSOLUTE_SECONDS");
 
 #[derive(Template)]
 #[template(path = "index.html")]
 struct IndexTemplate {
     is_logged_in: bool,
     user_name: String,
-    websocket_url: Option<&'static str>,
+    websocket_url: &'static str,
     enable_websockets: bool,
+    /// The newest fragment when the page was loaded, which server-sent events and long polls
+    /// carry on from
+    fragment_id: u64,
+    login_error: Option<String>,
+}
+
+/// Where browsers connect to the websocket, or nothing when server-sent events are used instead
+fn websocket_url() -> Option<&'static str> {
+    let websocket_url = WEBSOCKET_CONNECT_URL.unwrap_or(DEFAULT_WEBSOCKET_URL);
+
+    match websocket_url.is_empty() {
+        true => None,
+        false => Some(websocket_url),
+    }
 }
 
 ///
@@ -53,6 +123,7 @@ struct IndexTemplate {
 ///
 async fn index_view(
     ExtractSession(session): ExtractSession,
+    State(fragments): State<FragmentLog>,
     mut jar: CookieJar,
 ) -> impl IntoResponse {
     let user = get_user_from_session(&session);
@@ -60,26 +131,23 @@ async fn index_view(
     let mut is_logged_in = false;
     let mut user_name = "".to_string();
 
-    if let Ok(ref user) = user {
-        if let Some(user) = user {
-            is_logged_in = true;
-            user_name = user.name.clone();
-        }
+    if let Ok(Some(ref user)) = user {
+        is_logged_in = true;
+        user_name = user.name.clone();
     }
 
-    let cookie = Cookie::build(("session_id", session.id.clone()))
-        .secure(true)
-        .build();
-    jar = jar.add(cookie);
+    jar = jar.add(session_cookie(&session));
 
-    let websocket_url = WEBSOCKET_CONNECT_URL;
-    let enable_websockets = websocket_url.is_some();
+    let websocket_url = websocket_url().unwrap_or_default();
+    let enable_websockets = !websocket_url.is_empty();
 
     let template = IndexTemplate {
         is_logged_in,
         user_name,
         websocket_url,
         enable_websockets,
+        fragment_id: fragments.newest_id(),
+        login_error: None,
     };
 
     (jar, HtmlTemplate(template))
@@ -90,49 +158,211 @@ async fn index_view(
 struct LoginResultTemplate {
     user_name: String,
     is_logged_in: bool,
+    enable_websockets: bool,
+    login_error: Option<String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 struct LoginRequest {
+    /// The name to post messages under
+    #[schema(example = "Gamer")]
     name: String,
+    #[schema(example = "correct horse battery staple")]
+    password: String,
+}
+
+/// Signs the session in as the user, or shows the login form again with why they couldn't be
+///
+/// Signing in replaces the session with a new one, which the browser is sent a cookie for
+fn login_result(
+    state: &AppState,
+    session: Session,
+    jar: CookieJar,
+    user: Result<User, AccountError>,
+) -> (CookieJar, HtmlTemplate<LoginResultTemplate>) {
+    let failed = |jar: CookieJar, error: AccountError| {
+        if let AccountError::Database(ref e) = error {
+            eprintln!("Could not sign in: {}", e);
+        }
+
+        let template = LoginResultTemplate {
+            user_name: "".to_string(),
+            is_logged_in: false,
+            enable_websockets: websocket_url().is_some(),
+            login_error: Some(error.message()),
+        };
+
+        (jar, HtmlTemplate(template))
+    };
+
+    let user = match user {
+        Ok(user) => user,
+        Err(e) => return failed(jar, e),
+    };
+
+    let new_session = match rotate_session(&session.id, Some(user.id)) {
+        Ok(new_session) => new_session,
+        Err(e) => return failed(jar, AccountError::Database(e)),
+    };
+
+    state.events.publish(Event::UserLoggedIn {
+        user_id: user.id,
+        session_id: new_session.id.clone(),
+        previous_session_id: Some(session.id),
+    });
+
+    let template = LoginResultTemplate {
+        user_name: user.name,
+        is_logged_in: true,
+        enable_websockets: websocket_url().is_some(),
+        login_error: None,
+    };
+
+    (
+        jar.add(session_cookie(&new_session)),
+        HtmlTemplate(template),
+    )
 }
 
+///
+/// POST request to sign in, and return the header and message input for the signed in user
+///
+#[utoipa::path(
+    post,
+    path = "/login/",
+    tag = "html",
+    request_body(content = LoginRequest, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, description = "The new header and message input, swapped in out of band, or the login form with why signing in failed", content_type = "text/html"),
+    )
+)]
 async fn login_view(
+    State(state): State<AppState>,
     ExtractSession(session): ExtractSession,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    jar: CookieJar,
     Form(request): Form<LoginRequest>,
 ) -> impl IntoResponse {
-    let user = create_user(&request.name);
+    let address = client_address(&headers, peer);
+    let user = authenticate(&request.name, &request.password, address).await;
 
-    if user.is_err() {
-        return HtmlTemplate(LoginResultTemplate {
-            user_name: "".to_string(),
-            is_logged_in: false,
-        });
-    }
+    login_result(&state, session, jar, user)
+}
 
-    let user = user.unwrap();
+///
+/// POST request to register, signing in as the new user
+///
+#[utoipa::path(
+    post,
+    path = "/register/",
+    tag = "html",
+    request_body(content = LoginRequest, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, description = "The new header and message input, swapped in out of band, or the login form with why registering failed", content_type = "text/html"),
+    )
+)]
+async fn register_view(
+    State(state): State<AppState>,
+    ExtractSession(session): ExtractSession,
+    jar: CookieJar,
+    Form(request): Form<LoginRequest>,
+) -> impl IntoResponse {
+    let user = register_user(
+        &request.name,
+        &request.password,
+        session.user_id,
+        state.message_hooks,
+    )
+    .await;
 
-    if set_session_user(&session.id, user.id).is_err() {
-        return HtmlTemplate(LoginResultTemplate {
-            user_name: "".to_string(),
-            is_logged_in: false,
+    login_result(&state, session, jar, user)
+}
+
+#[derive(Template)]
+#[template(path = "logout_result.html")]
+struct LogoutResultTemplate {
+    user_name: String,
+    is_logged_in: bool,
+    enable_websockets: bool,
+    login_error: Option<String>,
+}
+
+///
+/// POST request to sign out, and return the header, message input and login form for nobody
+///
+#[utoipa::path(
+    post,
+    path = "/logout/",
+    tag = "html",
+    responses(
+        (status = 200, description = "The new header, message input and login form, swapped in out of band", content_type = "text/html"),
+        (status = 500, description = "The session couldn't be replaced"),
+    )
+)]
+async fn logout_view(
+    State(state): State<AppState>,
+    ExtractSession(session): ExtractSession,
+    jar: CookieJar,
+) -> Response {
+    // A new session, rather than the old one without its user, so the old ID is useless to anyone
+    // who might have it
+    let new_session = match rotate_session(&session.id, None) {
+        Ok(new_session) => new_session,
+        Err(e) => {
+            eprintln!("Could not sign out: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Could not sign out").into_response();
+        }
+    };
+
+    if let Some(user_id) = session.user_id {
+        state.events.publish(Event::UserLoggedOut {
+            user_id,
+            session_id: new_session.id.clone(),
+            previous_session_id: session.id,
         });
     }
 
-    HtmlTemplate(LoginResultTemplate {
-        user_name: user.name,
-        is_logged_in: true,
-    })
+    let template = LogoutResultTemplate {
+        user_name: "".to_string(),
+        is_logged_in: false,
+        enable_websockets: websocket_url().is_some(),
+        login_error: None,
+    };
+
+    (
+        jar.add(session_cookie(&new_session)),
+        HtmlTemplate(template),
+    )
+        .into_response()
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 struct CreateMessageRequest {
+    /// The text of the message
+    #[schema(example = "Hello everyone!")]
     message: String,
 }
 
 struct MessageDetail {
     message: Message,
     can_delete: bool,
+    /// Whether the message mentions whoever it's shown to
+    is_mention: bool,
+}
+
+impl MessageDetail {
+    /// How a message is shown to a user, or to someone who isn't logged in
+    fn new(message: Message, viewer: Option<&User>) -> Self {
+        let can_delete = viewer.is_some_and(|viewer| can_user_delete(&message, viewer));
+        let is_mention = viewer.is_some_and(|viewer| mentions_user(&message, viewer));
+
+        Self {
+            message,
+            can_delete,
+            is_mention,
+        }
+    }
 }
 
 #[derive(Template)]
@@ -143,10 +373,71 @@ struct GetMessagesTemplate {
     error: String,
 }
 
+/// Whether an `If-None-Match` header has the entity tag, compared weakly as the standard says to
+fn matches_entity_tag(headers: &HeaderMap, entity_tag: &str) -> bool {
+    let entity_tag = entity_tag.trim_start_matches("W/");
+
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == entity_tag)
+}
+
 ///
 /// GET request to load all messages
 ///
-async fn get_messages_view(ExtractSession(session): ExtractSession) -> impl IntoResponse {
+/// Responds with `304 Not Modified` when the `If-None-Match` header has the current `ETag`, which
+/// changes whenever a message is created or deleted
+///
+#[utoipa::path(
+    get,
+    path = "/message/",
+    tag = "html",
+    responses(
+        (status = 200, description = "Every message", content_type = "text/html"),
+        (status = 304, description = "The messages haven't changed"),
+    )
+)]
+async fn get_messages_view(
+    ExtractSession(session): ExtractSession,
+    headers: HeaderMap,
+) -> Response {
+    // Loaded before the messages, so the tag is never newer than what's sent with it
+    let version = match get_messages_version() {
+        Ok(version) => version,
+        Err(e) => {
+            let template = GetMessagesTemplate {
+                success: false,
+                messages: vec![],
+                error: format!("Error: {}", e),
+            };
+
+            return HtmlTemplate(template).into_response();
+        }
+    };
+
+    // Messages are rendered differently for each user
+    let entity_tag = format!(
+        "W/\"{}-{}-{}\"",
+        version.last_message_id,
+        version.deletions,
+        session.user_id.unwrap_or(0)
+    );
+
+    let cache_headers = [
+        (ETAG, entity_tag.clone()),
+        // Always checked with the server first, as the messages change all the time
+        (CACHE_CONTROL, "no-cache".to_string()),
+        (VARY, "Cookie".to_string()),
+    ];
+
+    if matches_entity_tag(&headers, &entity_tag) {
+        return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+    }
+
     let messages = get_messages();
 
     if let Err(e) = messages {
@@ -156,7 +447,7 @@ async fn get_messages_view(ExtractSession(session): ExtractSession) -> impl Into
             error: format!("Error: {}", e),
         };
 
-        return HtmlTemplate(template);
+        return HtmlTemplate(template).into_response();
     }
 
     // Get the logged in user
@@ -173,17 +464,7 @@ async fn get_messages_view(ExtractSession(session): ExtractSession) -> impl Into
     let messages = messages
         .unwrap()
         .into_iter()
-        .map(|message| {
-            let can_delete = match &user {
-                Some(user) => can_user_delete(&message, user),
-                None => false,
-            };
-
-            MessageDetail {
-                message,
-                can_delete,
-            }
-        })
+        .map(|message| MessageDetail::new(message, user.as_ref()))
         .collect();
 
     let template = GetMessagesTemplate {
@@ -191,43 +472,59 @@ async fn get_messages_view(ExtractSession(session): ExtractSession) -> impl Into
         messages,
         error: "".to_string(),
     };
-    HtmlTemplate(template)
+
+    (cache_headers, HtmlTemplate(template)).into_response()
 }
 
 #[derive(Template)]
 #[template(path = "new_message.html")]
 struct NewMessageTemplate {
     message_detail: Option<MessageDetail>,
-    error: Option<&'static str>,
+    /// Messages posted by message hooks in reply to the new message
+    replies: Vec<MessageDetail>,
+    error: Option<String>,
 }
 
 #[derive(Clone)]
 struct AppState {
+    events: EventBus,
+    message_hooks: &'static MessageHooks,
+    fragments: FragmentLog,
     websocket_handler: &'static Mutex<WebSocketHandler>,
 }
 
+impl FromRef<AppState> for EventBus {
+    fn from_ref(state: &AppState) -> Self {
+        state.events.clone()
+    }
+}
+
+impl FromRef<AppState> for FragmentLog {
+    fn from_ref(state: &AppState) -> Self {
+        state.fragments.clone()
+    }
+}
+
 ///
 /// POST request to create a new message, and return the newly created message as HTML
 ///
+#[utoipa::path(
+    post,
+    path = "/create-message/",
+    tag = "html",
+    request_body(content = CreateMessageRequest, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 201, description = "The new message, appended to #messages out of band", content_type = "text/html"),
+        (status = 400, description = "The message is invalid", content_type = "text/html"),
+        (status = 401, description = "Not logged in", content_type = "text/html"),
+        (status = 422, description = "A message hook rejected the message", content_type = "text/html"),
+    )
+)]
 async fn create_message_view(
     State(state): State<AppState>,
     ExtractSession(session): ExtractSession,
     Form(message_data): Form<CreateMessageRequest>,
 ) -> impl IntoResponse {
-    let text = &message_data.message;
-
-    let validation = validate_message(text);
-
-    if let Err(_e) = validation {
-        return (
-            StatusCode::BAD_REQUEST,
-            HtmlTemplate(NewMessageTemplate {
-                message_detail: None,
-                error: Some("Invalid message"),
-            }),
-        );
-    }
-
     let user_id = session.user_id;
 
     if user_id.is_none() {
@@ -235,7 +532,8 @@ async fn create_message_view(
             StatusCode::UNAUTHORIZED,
             HtmlTemplate(NewMessageTemplate {
                 message_detail: None,
-                error: Some("Not logged in"),
+                replies: vec![],
+                error: Some("Not logged in".to_string()),
             }),
         );
     }
@@ -248,7 +546,8 @@ async fn create_message_view(
                 StatusCode::UNAUTHORIZED,
                 HtmlTemplate(NewMessageTemplate {
                     message_detail: None,
-                    error: Some("Not logged in"),
+                    replies: vec![],
+                    error: Some("Not logged in".to_string()),
                 }),
             );
         }
@@ -259,47 +558,58 @@ async fn create_message_view(
             StatusCode::UNAUTHORIZED,
             HtmlTemplate(NewMessageTemplate {
                 message_detail: None,
-                error: Some("Not logged in"),
+                replies: vec![],
+                error: Some("Not logged in".to_string()),
             }),
         );
     }
 
     let user = user.unwrap();
 
-    // Add the new message to the list of messages
-    let message = create_message(&message_data.message, user.id);
-
-    if message.is_err() {
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            HtmlTemplate(NewMessageTemplate {
-                message_detail: None,
-                error: Some("Error creating message"),
-            }),
-        );
-    }
+    let posted = post_message(
+        &message_data.message,
+        &user,
+        state.message_hooks,
+        &state.events,
+    )
+    .await;
 
-    let message = message.unwrap();
+    let (message, replies) = match posted {
+        Ok(posted) => posted,
+        Err(e) => {
+            let (status, error) = match e {
+                PostMessageError::Invalid => {
+                    (StatusCode::BAD_REQUEST, "Invalid message".to_string())
+                }
+                PostMessageError::Rejected(reason) => (StatusCode::UNPROCESSABLE_ENTITY, reason),
+                PostMessageError::Database(e) => (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Error creating message: {e}"),
+                ),
+            };
 
-    // Broadcast to all active clients that a new message was created
-    let mut websocket_handler = state.websocket_handler.lock().unwrap();
+            return (
+                status,
+                HtmlTemplate(NewMessageTemplate {
+                    message_detail: None,
+                    replies: vec![],
+                    error: Some(error),
+                }),
+            );
+        }
+    };
 
-    let can_delete = can_user_delete(&message, &user);
+    let replies = replies
+        .into_iter()
+        .map(|message| MessageDetail::new(message, Some(&user)))
+        .collect();
 
     let template = NewMessageTemplate {
-        message_detail: Some(MessageDetail {
-            message,
-            can_delete,
-        }),
+        message_detail: Some(MessageDetail::new(message, Some(&user))),
+        replies,
         error: None,
     };
 
-    if let Ok(html) = template.render() {
-        if let Err(e) = websocket_handler.broadcast(&html) {
-            eprintln!("Websocket broadcasting error: {}", e);
-        }
-    };
-
     (StatusCode::CREATED, HtmlTemplate(template))
 }
 
@@ -314,7 +624,20 @@ struct DeleteMessageTemplate {
 /// View to delete a message
 ///
 /// The requesting user must be logged on and have created the message
+#[utoipa::path(
+    delete,
+    path = "/delete/{message_id}/",
+    tag = "html",
+    params(("message_id" = i32, Path, description = "The message's ID")),
+    responses(
+        (status = 200, description = "Hides the message out of band", content_type = "text/html"),
+        (status = 401, description = "Not logged in", content_type = "text/html"),
+        (status = 403, description = "The message belongs to someone else", content_type = "text/html"),
+        (status = 404, description = "No message has the ID", content_type = "text/html"),
+    )
+)]
 async fn delete_message_view(
+    State(state): State<AppState>,
     ExtractSession(session): ExtractSession,
     Path(message_id): Path<i32>,
 ) -> impl IntoResponse {
@@ -359,57 +682,34 @@ async fn delete_message_view(
 
     let user = user.unwrap();
 
-    let message = match get_message_by_id(message_id) {
+    let message = match remove_message(message_id, &user, &state.events).await {
         Ok(message) => message,
         Err(e) => {
+            let (status, error) = match e {
+                DeleteMessageError::NotFound => (
+                    StatusCode::NOT_FOUND,
+                    format!("Message {message_id} does not exist"),
+                ),
+                DeleteMessageError::Forbidden => {
+                    (StatusCode::FORBIDDEN, "Permission denied".to_string())
+                }
+                DeleteMessageError::Database(e) => (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Error deleting message: {e}"),
+                ),
+            };
+
             return (
-                StatusCode::NOT_FOUND,
+                status,
                 HtmlTemplate(DeleteMessageTemplate {
                     success: false,
                     message_id: None,
-                    error: format!("Failed to retrieve message: {e}"),
+                    error,
                 }),
-            )
+            );
         }
     };
 
-    if message.is_none() {
-        return (
-            StatusCode::NOT_FOUND,
-            HtmlTemplate(DeleteMessageTemplate {
-                success: false,
-                message_id: None,
-                error: format!("Message {message_id} does not exist"),
-            }),
-        );
-    }
-
-    let message = message.unwrap();
-
-    let can_delete = can_user_delete(&message, &user);
-
-    if !can_delete {
-        return (
-            StatusCode::FORBIDDEN,
-            HtmlTemplate(DeleteMessageTemplate {
-                success: false,
-                message_id: None,
-                error: "Permission denied".to_string(),
-            }),
-        );
-    }
-
-    if let Err(e) = delete_message(message_id) {
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            HtmlTemplate(DeleteMessageTemplate {
-                success: true,
-                message_id: None,
-                error: format!("Error deleting message: {e}"),
-            }),
-        );
-    };
-
     (
         StatusCode::OK,
         HtmlTemplate(DeleteMessageTemplate {
@@ -420,82 +720,250 @@ async fn delete_message_view(
     )
 }
 
-#[tokio::main]
-async fn main() {
-    run_migrations().expect("Could not run migrations");
-    let server =
-        std::net::TcpListener::bind(WEBSOCKET_ADDRESS).expect("Could not start websocket server.");
-
-    let websocket_handler = Box::new(Mutex::new(WebSocketHandler::new()));
-
-    // This lives in state but is meant to be static for the entire runtime of the program, so
-    // having it leaked doesn't seem like a big deal
-    // Having it static satisfies the state's Clone derivation requirement
-    let websocket_handler: &'static Mutex<WebSocketHandler> = Box::leak(websocket_handler);
+#[derive(Template)]
+#[template(path = "typing.html")]
+struct TypingTemplate<'a> {
+    name: &'a str,
+}
 
-    let state = AppState { websocket_handler };
+/// Renders the HTML sent to live clients for an event as a user would see it, if they need to know
+/// about it
+fn render_event_for(event: &Event, viewer: Option<&User>) -> Option<String> {
+    let html = match event {
+        Event::MessageCreated(message) => NewMessageTemplate {
+            message_detail: Some(MessageDetail::new(message.clone(), viewer)),
+            replies: vec![],
+            error: None,
+        }
+        .render(),
+        Event::MessageDeleted(message) => DeleteMessageTemplate {
+            success: true,
+            message_id: Some(message.id),
+            error: "".to_string(),
+        }
+        .render(),
+        // Nobody needs telling they're typing
+        Event::UserTyping { user_id, .. } if viewer.is_some_and(|viewer| viewer.id == *user_id) => {
+            return None
+        }
+        Event::UserTyping { name, .. } => TypingTemplate { name }.render(),
+        _ => return None,
+    };
 
-    std::thread::spawn(move || {
-        for stream in server.incoming() {
-            if let Err(e) = stream {
-                eprintln!("Error with incoming stream: {}", e);
-                continue;
-            }
+    match html {
+        Ok(html) => Some(html),
+        Err(e) => {
+            eprintln!("Failed to render {} for websockets: {}", event.name(), e);
+            None
+        }
+    }
+}
 
-            let stream = stream.unwrap();
-            let peer_addr = stream.peer_addr();
+/// The JSON sent to live clients other than htmx for an event, if they need to know about it
+fn event_data_for(event: &Event, viewer: Option<&User>) -> Option<serde_json::Value> {
+    match event {
+        Event::MessageCreated(message) | Event::MessageDeleted(message) => {
+            Some(json!({ "message": message }))
+        }
+        Event::UserTyping { user_id, .. } if viewer.is_some_and(|viewer| viewer.id == *user_id) => {
+            None
+        }
+        Event::UserTyping { user_id, name } => Some(json!({ "user_id": user_id, "name": name })),
+        _ => None,
+    }
+}
 
-            // let callback = |_request: &WebsocketRequest, mut response: WebsocketResponse| {
-            //     let headers = response.headers_mut();
-            //
-            //     headers.append("Access-Control-Allow-Methods", "GET, POST".parse().unwrap());
-            //
-            //     Ok(response)
-            // };
+/// Renders an event for a live client, in the form its protocol asks for
+fn render_payload_for(event: &Event, protocol: Protocol, viewer: Option<&User>) -> Option<Payload> {
+    match protocol {
+        Protocol::Html => render_event_for(event, viewer).map(Payload::Html),
+        Protocol::Json => event_data_for(event, viewer).map(Payload::Data),
+    }
+}
 
-            let websocket_accept = tungstenite::accept(stream);
+/// Renders the HTML sent to live clients for an event as someone who isn't logged in would see it
+fn render_event(event: &Event) -> Option<String> {
+    render_event_for(event, None)
+}
 
-            if let Err(ref e) = websocket_accept {
-                eprintln!("Error accepting websocket stream: {}", e);
+/// Broadcasts every chat event to all active websocket clients, rendered for each of them
+async fn broadcast_events(
+    websocket_handler: &'static Mutex<WebSocketHandler>,
+    mut receiver: Receiver<Event>,
+) {
+    loop {
+        let event = match receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(count)) => {
+                eprintln!("Websockets missed {} events", count);
                 continue;
             }
+            Err(RecvError::Closed) => return,
+        };
 
-            let mut websocket = websocket_accept.unwrap();
+        // Websockets connected with the session need to follow it to its new ID, and be shown what
+        // its new user can see
+        match &event {
+            Event::UserLoggedIn {
+                user_id,
+                session_id,
+                previous_session_id: Some(previous_session_id),
+            } => {
+                let user_id = *user_id;
+                let user = spawn_blocking(move || retrieve_user(user_id))
+                    .await
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+
+                match user {
+                    Ok(Some(user)) => websocket_handler.lock().unwrap().move_session(
+                        previous_session_id,
+                        session_id,
+                        Some(user),
+                    ),
+                    Ok(None) => {}
+                    Err(e) => eprintln!("Failed to load user {} for websockets: {}", user_id, e),
+                }
 
-            if let Err(e) =
-                websocket.send(tungstenite::Message::Text("Hello from server!".to_string()))
-            {
-                eprintln!("Failed to send message to websocket: {}", e);
+                continue;
             }
+            Event::UserLoggedIn { .. } => continue,
+            Event::UserLoggedOut {
+                session_id,
+                previous_session_id,
+                ..
+            } => {
+                websocket_handler.lock().unwrap().move_session(
+                    previous_session_id,
+                    session_id,
+                    None,
+                );
 
-            let lock = websocket_handler.lock();
-
-            if let Err(ref e) = lock {
-                eprintln!("Failed to lock websocket handler: {}", e);
                 continue;
             }
+            _ => {}
+        }
 
-            let mut lock = lock.unwrap();
+        let mut websocket_handler = websocket_handler.lock().unwrap();
 
-            println!("Accepted websocket from {:?}", peer_addr);
-            lock.add_websocket(websocket);
+        // Remembered under the same lock as the broadcast, so websockets which reconnect are sent
+        // each message exactly once
+        match &event {
+            Event::MessageCreated(message) => websocket_handler.remember(message.clone()),
+            Event::MessageDeleted(message) => websocket_handler.forget(message.id),
+            _ => {}
         }
-    });
 
-    let static_dir = ServeDir::new("static");
+        websocket_handler.broadcast(event.name(), |protocol, viewer| {
+            render_payload_for(&event, protocol, viewer)
+        });
+    }
+}
 
-    println!("WebSocket server listening at {}...", WEBSOCKET_ADDRESS);
+/// Builds the application's routes
+fn app(state: AppState) -> Router {
+    let static_dir = ServeDir::new("static");
 
-    // build our application with a route
-    let app = Router::new()
+    Router::new()
         .route("/", get(index_view))
         .route("/login/", post(login_view))
+        .route("/register/", post(register_view))
+        .route("/logout/", post(logout_view))
         .route("/message/", get(get_messages_view))
         .route("/create-message/", post(create_message_view))
         .route("/delete/:message_id/", delete(delete_message_view))
+        .route("/events/", get(events_view))
+        .route("/poll/", get(poll_view))
+        .route("/ws/", get(websocket_view))
+        .route("/profile/", get(own_profile_view))
+        .route(
+            "/user/:user_id/",
+            get(profile_view).post(update_profile_view),
+        )
+        .route("/user/:user_id/card/", get(profile_card_view))
+        .route("/user/:user_id/avatar/", get(avatar_view))
+        .route(
+            "/admin/webhooks/",
+            get(webhooks_view).post(create_webhook_view),
+        )
+        .route("/admin/webhooks/:webhook_id/", delete(delete_webhook_view))
+        .route(
+            "/admin/scripts/",
+            get(scripts_view).post(create_script_view),
+        )
+        .route("/admin/scripts/:script_id/", delete(delete_script_view))
+        .nest("/api/v1", api::routes())
+        .merge(graphql::routes(&state))
+        .merge(SwaggerUi::new("/api/docs").url("/api/openapi.json", ApiDoc::openapi()))
         .nest_service("/static", static_dir)
-        .with_state(state);
+        .with_state(state)
+}
+
+#[tokio::main]
+async fn main() {
+    run_migrations().expect("Could not run migrations");
+
+    let websocket_handler = Box::new(Mutex::new(WebSocketHandler::new()));
+
+    // This lives in state but is meant to be static for the entire runtime of the program, so
+    // having it leaked doesn't seem like a big deal
+    // Having it static satisfies the state's Clone derivation requirement
+    let websocket_handler: &'static Mutex<WebSocketHandler> = Box::leak(websocket_handler);
+
+    // Register hooks which process every new message
+    let mut message_hooks = MessageHooks::new();
+
+    if let Some(blocked_words) = BLOCKED_WORDS {
+        message_hooks.register(WordFilter::new(blocked_words));
+    }
+
+    message_hooks.register(ScriptHook);
+
+    // Like the websocket handler, hooks live for the entire runtime of the program
+    let message_hooks: &'static MessageHooks = Box::leak(Box::new(message_hooks));
+
+    // Everything which reacts to chat activity subscribes to the event bus
+    let events = EventBus::new();
+
+    tokio::spawn(broadcast_events(websocket_handler, events.subscribe()));
+    tokio::spawn(run_reaper(websocket_handler));
+    tokio::spawn(run_session_purge());
+    tokio::spawn(run_audit_log(events.subscribe_local()));
+
+    // Other instances sharing the database push this instance's events to their clients, and vice
+    // versa
+    let fanout = Fanout::new(events.clone()).expect("Could not read events");
+    tokio::spawn(fanout.run(events.queue_local()));
+
+    // Server-sent event clients are sent the same fragments as websockets
+    let fragments = FragmentLog::new();
+    tokio::spawn(fragments.clone().run(events.subscribe()));
+
+    let state = AppState {
+        events,
+        message_hooks,
+        fragments,
+        websocket_handler,
+    };
+
+    // Send webhook deliveries in the background so receivers never hold up a request
+    tokio::spawn(run_delivery_worker());
 
     let listener = TcpListener::bind(API_ADDRESS).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+
+    match (TLS_CERTIFICATE_PATH, TLS_KEY_PATH) {
+        (Some(certificate_path), Some(key_path)) => {
+            let certificate = TlsCertificate::load(certificate_path, key_path)
+                .expect("Could not load TLS certificate");
+
+            tokio::spawn(certificate.clone().watch());
+
+            tls::serve(listener, app(state), certificate).await;
+        }
+        _ => axum::serve(
+            listener,
+            app(state).into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap(),
+    }
 }
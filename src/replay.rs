@@ -0,0 +1,80 @@
+use std::collections::VecDeque;
+
+use crate::database::message::Message;
+
+/// How many recently broadcast messages are kept in memory for clients which reconnect
+const REPLAY_CAPACITY: usize = 256;
+
+/// The most messages replayed to a reconnecting client, anything older is left to the full reload
+pub const MAX_REPLAY: usize = 200;
+
+/// The messages most recently broadcast to live clients, so clients which lost their connection
+/// can be sent what they missed
+///
+/// Only messages which have already been broadcast are replayed, so nothing is sent twice to a
+/// client which reconnects while a message is on its way out
+#[derive(Default)]
+pub struct ReplayBuffer {
+    recent: VecDeque<Message>,
+    /// Every broadcast message from this ID onwards is in `recent`, unless it was deleted
+    complete_from: Option<i32>,
+    last_broadcast_id: Option<i32>,
+}
+
+impl ReplayBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remembers a message which is about to be broadcast
+    pub fn remember(&mut self, message: Message) {
+        if self.recent.len() == REPLAY_CAPACITY {
+            if let Some(evicted) = self.recent.pop_front() {
+                self.complete_from = Some(evicted.id + 1);
+            }
+        }
+
+        self.complete_from.get_or_insert(message.id);
+        self.last_broadcast_id = Some(message.id);
+        self.recent.push_back(message);
+    }
+
+    /// Forgets a deleted message, so it isn't replayed
+    pub fn forget(&mut self, message_id: i32) {
+        self.recent.retain(|message| message.id != message_id);
+    }
+
+    /// Whether messages after `last_message_id` need loading from the database, as some of them
+    /// may be older than what's kept in memory
+    pub fn needs_database(&self, last_message_id: i32) -> bool {
+        match self.complete_from {
+            Some(complete_from) => last_message_id + 1 < complete_from,
+            None => true,
+        }
+    }
+
+    /// Every broadcast message created after `last_message_id`, oldest first, using `stored` for
+    /// anything which isn't kept in memory
+    pub fn missed(&self, last_message_id: i32, stored: Vec<Message>) -> Vec<Message> {
+        let complete_from = self.complete_from;
+        let last_broadcast_id = self.last_broadcast_id;
+
+        let mut missed: Vec<Message> = stored
+            .into_iter()
+            .filter(|message| message.id > last_message_id)
+            .filter(|message| complete_from.is_none_or(|complete_from| message.id < complete_from))
+            // Newer messages haven't been broadcast yet, so will be sent live
+            .filter(|message| last_broadcast_id.is_none_or(|last| message.id <= last))
+            .collect();
+
+        missed.extend(
+            self.recent
+                .iter()
+                .filter(|message| message.id > last_message_id)
+                .cloned(),
+        );
+
+        let skipped = missed.len().saturating_sub(MAX_REPLAY);
+        missed.split_off(skipped)
+    }
+}
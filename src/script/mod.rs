@@ -0,0 +1,148 @@
+use std::time::{Duration, Instant};
+
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{Array, Dynamic, Engine, Map, Scope, AST};
+
+use crate::database::message::{get_messages_page, Message};
+use crate::database::script::get_scripts;
+use crate::database::user::User;
+use crate::hooks::MessageHook;
+
+pub mod views;
+
+/// How many operations a script can run before it is stopped
+const MAX_OPERATIONS: u64 = 100_000;
+/// How long a script can run before it is stopped
+const TIME_LIMIT: Duration = Duration::from_millis(100);
+/// The most messages a script can read at once
+const MAX_RECENT_MESSAGES: u32 = 50;
+
+fn message_to_map(message: &Message) -> Map {
+    let mut map = Map::new();
+
+    map.insert("id".into(), (message.id as i64).into());
+    map.insert("text".into(), message.text.clone().into());
+    map.insert("author".into(), message.author_name.clone().into());
+
+    map
+}
+
+/// Creates a sandboxed script engine
+///
+/// Scripts have no access to the file system or network, are limited in how long they can run for
+/// and how much memory they can use, and can only read recent messages
+pub fn create_engine() -> Engine {
+    let mut engine = Engine::new();
+
+    // Scripts can't import other files or evaluate strings as code
+    engine.set_module_resolver(DummyModuleResolver::new());
+    engine.disable_symbol("eval");
+    engine.on_print(|_| {});
+    engine.on_debug(|_, _, _| {});
+
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine.set_max_call_levels(16);
+    engine.set_max_expr_depths(64, 32);
+    engine.set_max_string_size(4096);
+    engine.set_max_array_size(1024);
+    engine.set_max_map_size(256);
+
+    // Stop the script once it has used up its time, even if it has operations left
+    let started_at = Instant::now();
+    engine.on_progress(move |_| (started_at.elapsed() > TIME_LIMIT).then_some(Dynamic::UNIT));
+
+    // The most recent messages, oldest first
+    engine.register_fn("recent_messages", |count: i64| -> Array {
+        let count = count.clamp(0, MAX_RECENT_MESSAGES as i64) as u32;
+        let messages = get_messages_page(None, count).unwrap_or_default();
+
+        messages
+            .iter()
+            .rev()
+            .map(|message| message_to_map(message).into())
+            .collect()
+    });
+
+    engine
+}
+
+fn has_function(ast: &AST, name: &str, params: usize) -> bool {
+    ast.iter_functions()
+        .any(|function| function.name == name && function.params.len() == params)
+}
+
+/// Runs a script against a new message, returning its reply if it made one
+///
+/// A message like "/roll 2d6" calls the script's `command_roll(args, message)` function, if it has
+/// one. Every message also calls `on_message(message)`. Returning a string from either posts it as
+/// a reply.
+pub fn run_script(source: &str, message: &Message) -> Result<Option<String>, String> {
+    let engine = create_engine();
+    let ast = engine.compile(source).map_err(|e| e.to_string())?;
+
+    let mut calls: Vec<(String, Vec<Dynamic>)> = Vec::new();
+
+    if let Some(command) = message.text.strip_prefix('/') {
+        let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+
+        if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            let function = format!("command_{name}");
+
+            if has_function(&ast, &function, 2) {
+                calls.push((function, vec![args.into(), message_to_map(message).into()]));
+            }
+        }
+    }
+
+    if has_function(&ast, "on_message", 1) {
+        calls.push((
+            "on_message".to_string(),
+            vec![message_to_map(message).into()],
+        ));
+    }
+
+    let mut scope = Scope::new();
+
+    for (function, args) in calls {
+        let result: Dynamic = engine
+            .call_fn(&mut scope, &ast, &function, args)
+            .map_err(|e| e.to_string())?;
+
+        if let Some(reply) = result.into_string().ok().filter(|reply| !reply.is_empty()) {
+            return Ok(Some(reply));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Runs every uploaded script over new messages
+///
+/// Scripts are tried in the order they were uploaded, and the first reply is posted
+pub struct ScriptHook;
+
+impl MessageHook for ScriptHook {
+    fn name(&self) -> &str {
+        "Bot"
+    }
+
+    fn after_create(&self, message: &Message, _author: &User) -> Option<String> {
+        let scripts = match get_scripts() {
+            Ok(scripts) => scripts,
+            Err(e) => {
+                eprintln!("Failed to load scripts: {}", e);
+                return None;
+            }
+        };
+
+        for script in scripts {
+            match run_script(&script.source, message) {
+                Ok(Some(reply)) => return Some(reply),
+                Ok(None) => {}
+                Err(e) => eprintln!("Script {} failed: {}", script.name, e),
+            }
+        }
+
+        None
+    }
+}
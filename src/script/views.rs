@@ -0,0 +1,111 @@
+use askama::Template;
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Form;
+use serde::Deserialize;
+
+use super::create_engine;
+use crate::database::script::{create_script, delete_script, get_scripts, Script};
+use crate::extractors::{ExtractSession, SameOrigin};
+use crate::template::HtmlTemplate;
+use crate::time::now;
+use crate::user::require_admin;
+
+#[derive(Template)]
+#[template(path = "scripts.html")]
+struct ScriptsTemplate {
+    scripts: Vec<Script>,
+}
+
+///
+/// GET request to load the bot script admin page
+///
+pub async fn scripts_view(ExtractSession(session): ExtractSession) -> Response {
+    if let Err(e) = require_admin(&session) {
+        return e.into_response();
+    }
+
+    match get_scripts() {
+        Ok(scripts) => HtmlTemplate(ScriptsTemplate { scripts }).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to load scripts: {e}"),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(Template)]
+#[template(path = "script.html")]
+struct ScriptTemplate {
+    script: Script,
+}
+
+#[derive(Deserialize)]
+pub struct CreateScriptRequest {
+    name: String,
+    source: String,
+}
+
+///
+/// POST request to upload a new bot script, and return it as HTML
+///
+/// Only from the app's own pages, so other sites can't upload scripts as a signed in admin
+///
+pub async fn create_script_view(
+    _: SameOrigin,
+    ExtractSession(session): ExtractSession,
+    Form(request): Form<CreateScriptRequest>,
+) -> Response {
+    if let Err(e) = require_admin(&session) {
+        return e.into_response();
+    }
+
+    if request.name.trim().is_empty() {
+        return (StatusCode::BAD_REQUEST, "Scripts need a name").into_response();
+    }
+
+    // Catch syntax errors now rather than when a message is sent
+    if let Err(e) = create_engine().compile(&request.source) {
+        return (StatusCode::BAD_REQUEST, format!("Invalid script: {e}")).into_response();
+    }
+
+    match create_script(request.name.trim(), &request.source, now()) {
+        Ok(script) => {
+            (StatusCode::CREATED, HtmlTemplate(ScriptTemplate { script })).into_response()
+        }
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error creating script: {e}"),
+        )
+            .into_response(),
+    }
+}
+
+///
+/// DELETE request to remove a bot script
+///
+pub async fn delete_script_view(
+    _: SameOrigin,
+    ExtractSession(session): ExtractSession,
+    Path(script_id): Path<i32>,
+) -> Response {
+    if let Err(e) = require_admin(&session) {
+        return e.into_response();
+    }
+
+    match delete_script(script_id) {
+        Ok(0) => (
+            StatusCode::NOT_FOUND,
+            format!("Script {script_id} does not exist"),
+        )
+            .into_response(),
+        Ok(_) => StatusCode::OK.into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error deleting script: {e}"),
+        )
+            .into_response(),
+    }
+}
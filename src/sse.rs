@@ -0,0 +1,87 @@
+use std::convert::Infallible;
+
+use std::net::SocketAddr;
+
+use axum::extract::{ConnectInfo, Query, State};
+use axum::http::HeaderMap;
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use futures_util::stream::{self, StreamExt};
+use serde::Deserialize;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::fragments::{Fragment, Missed};
+use crate::websocket::accept_connection;
+use crate::AppState;
+
+/// Sent by browsers when they reconnect, with the ID of the last event they received
+const LAST_EVENT_ID_HEADER: &str = "Last-Event-ID";
+/// Tells clients they missed more than can be sent again, so they need to load the messages again
+const RESET_EVENT: &str = "reset";
+
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    /// Where the page's first connection starts, since browsers can only set `Last-Event-ID`
+    /// when they reconnect
+    last_id: Option<u64>,
+}
+
+fn to_sse_event(fragment: Fragment) -> Result<SseEvent, Infallible> {
+    Ok(SseEvent::default()
+        .id(fragment.id.to_string())
+        .data(fragment.html))
+}
+
+///
+/// GET request to stream the same HTML fragments sent over websockets, as server-sent events
+///
+/// Clients which reconnect with `Last-Event-ID`, or connect with `last_id`, are sent the fragments
+/// they missed first, or a `reset` event if they missed too many
+///
+/// Streams are only opened for browsers from allowed origins, and count against the same limits as
+/// websockets
+///
+pub async fn events_view(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(query): Query<EventsQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let slot = match accept_connection(state.websocket_handler, &headers, peer) {
+        Ok(slot) => slot,
+        Err(refused) => return refused.into_response(),
+    };
+
+    let fragments = state.fragments;
+    let last_id = headers
+        .get(LAST_EVENT_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .or(query.last_id);
+
+    let (missed, receiver) = fragments.subscribe(last_id);
+
+    // The slot is held for as long as the stream is open
+    let live = stream::unfold((receiver, slot), |(mut receiver, slot)| async move {
+        match receiver.recv().await {
+            Ok(fragment) => Some((fragment, (receiver, slot))),
+            // Ending the stream makes the browser reconnect with the last ID it saw, so it's
+            // sent what it missed
+            Err(RecvError::Lagged(_)) | Err(RecvError::Closed) => None,
+        }
+    });
+
+    let missed = match missed {
+        Missed::Fragments(fragments) => fragments.into_iter().map(to_sse_event).collect(),
+        Missed::Everything { newest_id } => vec![Ok(SseEvent::default()
+            .id(newest_id.to_string())
+            .event(RESET_EVENT)
+            .data(""))],
+    };
+
+    let fragments = stream::iter(missed).chain(live.map(to_sse_event));
+
+    Sse::new(fragments)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
@@ -1,42 +1,2442 @@
-use super::rocket;
-use rocket::form::validate::Contains;
-use rocket::http::{ContentType, Status};
-use rocket::local::blocking::Client;
+use std::sync::{Arc, Mutex, Once};
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::extract::connect_info::MockConnectInfo;
+use axum::extract::State;
+use axum::http::{header, HeaderMap, Request, StatusCode};
+use axum::response::Response;
+use axum::routing::post;
+use axum::Router;
+use http_body_util::BodyExt;
+use tokio::net::TcpListener;
+use tower::ServiceExt;
+
+use super::{app, broadcast_events, render_event, AppState};
+use crate::database::message::{create_message, mentions_user, Message};
+use crate::database::script::{create_script, delete_script};
+use crate::database::session::set_session_user;
+use crate::database::user::{create_user, retrieve_user_by_name, set_admin, User};
+use crate::database::webhook::{
+    create_webhook, delete_webhook, get_recent_deliveries, get_webhooks, DeliveryStatus,
+};
+use crate::database::{delete_test_database, run_migrations};
+use crate::envelope::{Payload, Protocol};
+use crate::events::{Event, EventBus};
+use crate::fanout::Fanout;
+use crate::fragments::FragmentLog;
+use crate::graphql::schema;
+use crate::hooks::word_filter::WordFilter;
+use crate::hooks::{MessageDraft, MessageHook, MessageHooks};
+use crate::script::{run_script, ScriptHook};
+use crate::tls::{self, TlsCertificate};
+use crate::user::names::{name_key, name_skeleton};
+use crate::webhook::{deliver_pending, dispatch_event, sign, SIGNATURE_HEADER};
+use crate::websocket::{is_allowed_origin, ConnectionRefused, WebSocketHandler};
+
+static MIGRATIONS: Once = Once::new();
+
+/// Stops tests from attempting the same pending delivery at the same time
+static DELIVERY_WORKER: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Migrates a new database for the tests, which is kept apart from the app's own
+fn setup_database() {
+    MIGRATIONS.call_once(|| {
+        delete_test_database();
+        run_migrations().expect("Could not run migrations")
+    });
+}
+
+fn client() -> Router {
+    client_with_hooks(MessageHooks::new())
+}
+
+fn client_with_hooks(message_hooks: MessageHooks) -> Router {
+    client_with_events(message_hooks, EventBus::new())
+}
+
+fn client_with_events(message_hooks: MessageHooks, events: EventBus) -> Router {
+    client_from(message_hooks, events, TEST_ADDRESS)
+}
+
+/// Where requests come from, unless a test needs them to come from somewhere else
+const TEST_ADDRESS: std::net::SocketAddr =
+    std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 4000);
+
+/// The app as though every request was made from `address`, unless it's really served
+fn client_from(
+    message_hooks: MessageHooks,
+    events: EventBus,
+    address: std::net::SocketAddr,
+) -> Router {
+    setup_database();
+
+    app(state(message_hooks, events)).layer(MockConnectInfo(address))
+}
+
+/// Builds the app's state, with the same background tasks as `main()` pushing live updates
+fn state(message_hooks: MessageHooks, events: EventBus) -> AppState {
+    let message_hooks = Box::leak(Box::new(message_hooks));
+    let websocket_handler = Box::leak(Box::new(Mutex::new(WebSocketHandler::new())));
+
+    let fragments = FragmentLog::new();
+    tokio::spawn(fragments.clone().run(events.subscribe()));
+    tokio::spawn(broadcast_events(websocket_handler, events.subscribe()));
+
+    AppState {
+        events,
+        message_hooks,
+        fragments,
+        websocket_handler,
+    }
+}
+
+async fn into_string(response: Response) -> String {
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+/// Loads the index page to get a session which isn't signed in, returning the session cookie
+async fn anonymous_session(client: &Router) -> String {
+    let response = client
+        .clone()
+        .oneshot(Request::get("/").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+
+    let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+    cookie.split(';').next().unwrap().to_string()
+}
+
+/// The user with a name, who is created if they don't exist yet, as names can only be used once
+fn user_named(name: &str) -> User {
+    match retrieve_user_by_name(name).unwrap() {
+        Some(user) => user,
+        None => create_user(name).unwrap(),
+    }
+}
+
+/// Every user the tests register has the same password
+const TEST_PASSWORD: &str = "correct horse battery staple";
+
+/// The session cookie a response sets, if it sets one
+fn set_cookie(response: &Response) -> Option<String> {
+    let cookie = response
+        .headers()
+        .get(header::SET_COOKIE)?
+        .to_str()
+        .unwrap();
+
+    Some(cookie.split(';').next().unwrap().to_string())
+}
+
+/// Posts a name and password to `/login/` or `/register/`, returning the HTML response along with
+/// the new session cookie if it worked
+async fn submit_login(
+    client: &Router,
+    cookie: &str,
+    path: &str,
+    name: &str,
+    password: &str,
+) -> (String, Option<String>) {
+    let response = client
+        .clone()
+        .oneshot(
+            Request::post(path)
+                .header(header::COOKIE, cookie)
+                .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .body(Body::from(format!("name={name}&password={password}")))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let cookie = set_cookie(&response);
+
+    (into_string(response).await, cookie)
+}
+
+/// Signs a session in, registering the user if they haven't been already, returning the cookie for
+/// the session which replaces it
+async fn sign_in(client: &Router, cookie: &str, name: &str) -> String {
+    let (body, new_cookie) = submit_login(client, cookie, "/register/", name, TEST_PASSWORD).await;
+
+    if body.contains("Welcome") {
+        return new_cookie.unwrap();
+    }
+
+    let (body, new_cookie) = submit_login(client, cookie, "/login/", name, TEST_PASSWORD).await;
+    assert!(
+        body.contains("Welcome"),
+        "Could not sign in as {name}: {body}"
+    );
+
+    new_cookie.unwrap()
+}
+
+/// Loads the index page to get a session, then signs in with it, returning the session cookie
+async fn login(client: &Router, name: &str) -> String {
+    let cookie = anonymous_session(client).await;
+
+    sign_in(client, &cookie, name).await
+}
+
+/// Registers a user through the API, returning the status along with the new session or error
+async fn api_register(client: &Router, name: &str) -> (StatusCode, serde_json::Value) {
+    let body = serde_json::json!({ "name": name, "password": TEST_PASSWORD }).to_string();
+
+    let response = client
+        .clone()
+        .oneshot(
+            Request::post("/api/v1/users/")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    (response.status(), into_json(response).await)
+}
+
+/// Registers a user through the API unless they already have been, then signs in as them,
+/// returning the new session
+async fn api_login(client: &Router, name: &str) -> serde_json::Value {
+    let (status, session) = api_register(client, name).await;
+
+    if status == StatusCode::CREATED {
+        return session;
+    }
+
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    let body = serde_json::json!({ "name": name, "password": TEST_PASSWORD }).to_string();
+
+    let response = client
+        .clone()
+        .oneshot(
+            Request::post("/api/v1/sessions/")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    into_json(response).await
+}
+
+async fn post_message(client: &Router, cookie: &str, message: &str) -> Response {
+    client
+        .clone()
+        .oneshot(
+            Request::post("/create-message/")
+                .header(header::COOKIE, cookie)
+                .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .body(Body::from(format!("message={message}")))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_index() {
+    let client = client();
+
+    let response = client
+        .oneshot(Request::get("/").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = into_string(response).await;
+    assert!(body.contains("JDP"));
+}
+
+async fn get_messages_with_tag(
+    client: &Router,
+    cookie: &str,
+    entity_tag: Option<&str>,
+) -> (StatusCode, String) {
+    let mut request = Request::get("/message/").header(header::COOKIE, cookie);
+
+    if let Some(entity_tag) = entity_tag {
+        request = request.header(header::IF_NONE_MATCH, entity_tag);
+    }
+
+    let response = client
+        .clone()
+        .oneshot(request.body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+
+    let entity_tag = response.headers()[header::ETAG]
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    (response.status(), entity_tag)
+}
+
+#[tokio::test]
+async fn test_messages_entity_tag() {
+    let client = client();
+    let cookie = login(&client, "Cacher").await;
+
+    post_message(&client, &cookie, "Cached").await;
+
+    // Nothing has changed, unless other tests posted in the meantime
+    let mut unchanged = None;
+
+    for _ in 0..10 {
+        let (status, entity_tag) = get_messages_with_tag(&client, &cookie, None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, again) = get_messages_with_tag(&client, &cookie, Some(&entity_tag)).await;
+
+        if again == entity_tag {
+            assert_eq!(status, StatusCode::NOT_MODIFIED);
+            unchanged = Some(entity_tag);
+            break;
+        }
+    }
+
+    let unchanged = unchanged.expect("The messages never stayed the same");
+
+    // Messages are rendered for each user, so they're tagged differently
+    let other_cookie = login(&client, "Other%20cacher").await;
+    let (_, other_tag) = get_messages_with_tag(&client, &other_cookie, None).await;
+    assert_ne!(other_tag, unchanged);
+
+    // Creating and deleting messages changes the tag
+    let response = post_message(&client, &cookie, "Uncached").await;
+    let body = into_string(response).await;
+    let message_id = body
+        .split("id=\"message-")
+        .nth(1)
+        .and_then(|rest| rest.split('"').next())
+        .unwrap()
+        .to_string();
+
+    let (status, created) = get_messages_with_tag(&client, &cookie, Some(&unchanged)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_ne!(created, unchanged);
+
+    let response = client
+        .clone()
+        .oneshot(
+            Request::delete(format!("/delete/{message_id}/"))
+                .header(header::COOKIE, &cookie)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let (status, deleted) = get_messages_with_tag(&client, &cookie, Some(&created)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_ne!(deleted, created);
+}
+
+#[tokio::test]
+async fn test_get_messages() {
+    let client = client();
+    let cookie = login(&client, "Tester").await;
+
+    // Create a new message
+    let response = post_message(&client, &cookie, "A%20test%20string!").await;
+
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let body = into_string(response).await;
+    assert!(body.contains("A test string!"));
+
+    // Check the new message appears upon GET
+    let response = client
+        .oneshot(
+            Request::get("/message/")
+                .header(header::COOKIE, &cookie)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = into_string(response).await;
+    assert!(body.contains("A test string!"));
+}
+
+async fn run_delivery_worker_once() {
+    let _lock = DELIVERY_WORKER.lock().await;
+
+    deliver_pending(&reqwest::Client::new()).await.unwrap();
+}
+
+type Received = Arc<Mutex<Vec<(HeaderMap, String)>>>;
+
+/// Starts a local stand-in for a webhook receiver which records every request and responds with
+/// the given status, returning its URL
+async fn start_receiver(status: StatusCode) -> (String, Received) {
+    let received = Received::default();
+
+    let receiver = Router::new()
+        .route(
+            "/",
+            post(
+                move |State(received): State<Received>, headers: HeaderMap, body: String| async move {
+                    received.lock().unwrap().push((headers, body));
+                    status
+                },
+            ),
+        )
+        .with_state(received.clone());
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+
+    tokio::spawn(async move { axum::serve(listener, receiver).await.unwrap() });
+
+    (url, received)
+}
+
+#[tokio::test]
+async fn test_webhook_delivery_is_signed() {
+    setup_database();
+
+    let (url, received) = start_receiver(StatusCode::OK).await;
+    let webhook = create_webhook(&url, "secret", 0).unwrap();
+
+    let user = user_named("Webhook tester");
+    let message = create_message("Hello webhook!", &[], user.id).unwrap();
+    dispatch_event(&Event::MessageCreated(message)).unwrap();
+
+    run_delivery_worker_once().await;
+    delete_webhook(webhook.id).unwrap();
+
+    let received = received.lock().unwrap();
+    let (headers, body) = received
+        .iter()
+        .find(|(_, body)| body.contains("Hello webhook!"))
+        .expect("The receiver was sent the message");
+
+    assert!(body.contains("message.created"));
+    assert_eq!(
+        headers[SIGNATURE_HEADER].to_str().unwrap(),
+        format!("sha256={}", sign("secret", body))
+    );
+}
+
+#[tokio::test]
+async fn test_webhook_delivery_is_retried() {
+    setup_database();
+
+    let (url, received) = start_receiver(StatusCode::INTERNAL_SERVER_ERROR).await;
+    let webhook = create_webhook(&url, "secret", 0).unwrap();
+
+    let user = user_named("Webhook retry tester");
+    let message = create_message("Retry webhook!", &[], user.id).unwrap();
+    dispatch_event(&Event::MessageCreated(message)).unwrap();
+
+    run_delivery_worker_once().await;
+
+    // The failed delivery is logged and backed off instead of being attempted straight away
+    let delivery = get_recent_deliveries(100)
+        .unwrap()
+        .into_iter()
+        .find(|delivery| delivery.url == url)
+        .expect("The delivery was logged");
+
+    assert_eq!(delivery.status, DeliveryStatus::Pending);
+    assert_eq!(delivery.attempts, 1);
+    assert_eq!(delivery.last_status_code, Some(500));
+
+    run_delivery_worker_once().await;
+    delete_webhook(webhook.id).unwrap();
+
+    assert_eq!(received.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_webhook_delivery_is_queued_with_message() {
+    let client = client();
+    let cookie = login(&client, "Webhook queue tester").await;
+
+    let (url, _) = start_receiver(StatusCode::OK).await;
+    let webhook = create_webhook(&url, "secret", 0).unwrap();
+
+    // Deliveries are queued as the message is posted, without relying on the event bus
+    let response = post_message(&client, &cookie, "Queued%20webhook!").await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let queued = get_recent_deliveries(100)
+        .unwrap()
+        .into_iter()
+        .any(|delivery| delivery.url == url && delivery.payload.contains("Queued webhook!"));
+
+    delete_webhook(webhook.id).unwrap();
+
+    assert!(queued);
+}
+
+#[tokio::test]
+async fn test_webhook_delivery_is_claimed_once() {
+    setup_database();
+
+    let (url, received) = start_receiver(StatusCode::OK).await;
+    let webhook = create_webhook(&url, "secret", 0).unwrap();
+
+    // Secrets aren't shown again once they're created
+    assert_eq!(webhook.masked_secret(), "••••cret");
+
+    let user = user_named("Webhook claim tester");
+    let message = create_message("Claim webhook!", &[], user.id).unwrap();
+    dispatch_event(&Event::MessageCreated(message)).unwrap();
+
+    // Two instances sharing the database don't both send the delivery
+    {
+        let _lock = DELIVERY_WORKER.lock().await;
+        let client = reqwest::Client::new();
+
+        let (first, second) = tokio::join!(deliver_pending(&client), deliver_pending(&client));
+        first.unwrap();
+        second.unwrap();
+    }
+
+    delete_webhook(webhook.id).unwrap();
+
+    assert_eq!(received.lock().unwrap().len(), 1);
+}
+
+/// Rejects messages mentioning spam, and replies to messages mentioning tickets
+struct TestHook;
+
+impl MessageHook for TestHook {
+    fn name(&self) -> &str {
+        "Ticket bot"
+    }
+
+    fn before_create(&self, draft: &mut MessageDraft, _author: &User) -> Result<(), String> {
+        if draft.text.contains("spam") {
+            return Err("No spam allowed".to_string());
+        }
+
+        Ok(())
+    }
+
+    fn after_create(&self, message: &Message, _author: &User) -> Option<String> {
+        message
+            .text
+            .contains("JDP-1")
+            .then(|| "JDP-1 is the ticket for fixing everything".to_string())
+    }
+}
+
+#[tokio::test]
+async fn test_message_hooks() {
+    let mut message_hooks = MessageHooks::new();
+    message_hooks.register(WordFilter::new("heck"));
+    message_hooks.register(TestHook);
+
+    let client = client_with_hooks(message_hooks);
+    let cookie = login(&client, "Hooked").await;
+
+    // Rejected messages aren't created
+    let response = post_message(&client, &cookie, "buy%20my%20spam").await;
+
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(into_string(response).await.contains("No spam allowed"));
+
+    // Messages can be rewritten, annotated and replied to
+    let response = post_message(&client, &cookie, "what%20the%20heck%20is%20JDP-1").await;
+
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let body = into_string(response).await;
+    assert!(body.contains("what the **** is JDP-1"));
+    assert!(body.contains("Edited by word filter"));
+    assert!(body.contains("Ticket bot"));
+    assert!(body.contains("JDP-1 is the ticket for fixing everything"));
+}
+
+#[tokio::test]
+async fn test_script_command() {
+    setup_database();
+
+    let script = create_script(
+        "echo",
+        "fn command_echo(args, message) { `${message.author} said ${args}` }",
+        0,
+    )
+    .unwrap();
+
+    let mut message_hooks = MessageHooks::new();
+    message_hooks.register(ScriptHook);
+
+    let client = client_with_hooks(message_hooks);
+    let cookie = login(&client, "Scripter").await;
+
+    let response = post_message(&client, &cookie, "/echo%20hello%20there").await;
+    delete_script(script.id).unwrap();
+
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert!(into_string(response)
+        .await
+        .contains("Scripter said hello there"));
+}
 
 #[test]
-fn test_index() {
-    let client = Client::tracked(rocket()).expect("valid rocket instance");
+fn test_script_reads_recent_messages() {
+    setup_database();
 
-    // TODO: Use the uri!() macro
-    let response = client.get("/").dispatch();
+    let user = user_named("Script reader");
+    create_message("First", &[], user.id).unwrap();
+    let latest = create_message("Second", &[], user.id).unwrap();
 
-    assert_eq!(response.status(), Status::Ok);
+    // Only as many messages as asked for are read, oldest first
+    let result = run_script(
+        "fn on_message(message) { let recent = recent_messages(2); `${recent.len()} ${recent[0].id < recent[1].id}` }",
+        &latest,
+    )
+    .unwrap()
+    .unwrap();
 
-    let body = response.into_string();
-    assert_eq!(body.contains("JDP"), true);
+    assert_eq!(result, "2 true");
 }
 
 #[test]
-fn test_get_messages() {
-    let client = Client::tracked(rocket()).expect("valid rocket instance");
+fn test_mentions() {
+    let bob = User::new(1, "Bob".to_string(), false);
+    let mentions = |text: &str| {
+        let message = Message::new(1, text.to_string(), 2, "Tester".to_string(), vec![]);
+        mentions_user(&message, &bob)
+    };
+
+    assert!(mentions("@bob"));
+    assert!(mentions("Hi @Bob, how are you?"));
+    assert!(mentions("@bobby and @bob"));
+    assert!(!mentions("@bobby"));
+    assert!(!mentions("@bob_2"));
+    assert!(!mentions("bob"));
+}
+
+#[test]
+fn test_script_is_stopped() {
+    let message = Message::new(1, "Hello".to_string(), 1, "Tester".to_string(), vec![]);
+
+    let result = run_script("fn on_message(message) { loop {} }", &message);
+
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_local_event_queue_keeps_everything() {
+    let events = EventBus::new();
+    let mut lagging = events.subscribe_local();
+    let mut queue = events.queue_local();
+
+    // Far more than subscribers are kept waiting for
+    for _ in 0..5000 {
+        events.publish(Event::SessionCreated);
+    }
+
+    assert!(matches!(
+        lagging.try_recv(),
+        Err(tokio::sync::broadcast::error::TryRecvError::Lagged(_))
+    ));
+
+    let mut queued = 0;
+    while queue.try_recv().is_ok() {
+        queued += 1;
+    }
+
+    assert_eq!(queued, 5000);
+}
+
+#[tokio::test]
+async fn test_events_are_published() {
+    let events = EventBus::new();
+    let mut receiver = events.subscribe();
+
+    let client = client_with_events(MessageHooks::new(), events);
+    let cookie = login(&client, "Publisher").await;
+
+    assert!(matches!(receiver.recv().await, Ok(Event::SessionCreated)));
+    assert!(matches!(
+        receiver.recv().await,
+        Ok(Event::UserLoggedIn { .. })
+    ));
+
+    post_message(&client, &cookie, "Publish%20me").await;
+
+    let Ok(Event::MessageCreated(message)) = receiver.recv().await else {
+        panic!("Expected a message to be created");
+    };
+
+    assert_eq!(message.text, "Publish me");
 
-    // Create a new message
     let response = client
-        .post("/create-message")
-        .header(ContentType::Form)
-        .body("message=A%20test%20string!")
-        .dispatch();
+        .oneshot(
+            Request::delete(format!("/delete/{}/", message.id))
+                .header(header::COOKIE, &cookie)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
 
-    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.status(), StatusCode::OK);
 
-    let body = response.into_string();
-    assert_eq!(body.contains("A test string!"), true);
+    let Ok(event @ Event::MessageDeleted(_)) = receiver.recv().await else {
+        panic!("Expected a message to be deleted");
+    };
 
-    // Check the new message appears upon GET
-    let response = client.get("/message").dispatch();
+    // Websocket clients are told to remove the message
+    let html = render_event(&event).unwrap();
+    assert!(html.contains(&format!("id=\"message-{}\"", message.id)));
+    assert!(html.contains("hx-swap-oob"));
+}
+
+async fn into_json(response: Response) -> serde_json::Value {
+    serde_json::from_str(&into_string(response).await).unwrap()
+}
+
+#[tokio::test]
+async fn test_api() {
+    let client = client();
+
+    // Sign in to get a token
+    let session = api_login(&client, "API tester").await;
+    let token = session["token"].as_str().unwrap();
+    assert_eq!(session["user"]["name"], "API tester");
+
+    // Messages can't be posted anonymously
+    let response = client
+        .clone()
+        .oneshot(
+            Request::post("/api/v1/messages/")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(r#"{"message": "Hello API"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(into_json(response).await["error"]["code"], "unauthorized");
+
+    let response = client
+        .clone()
+        .oneshot(
+            Request::post("/api/v1/messages/")
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(r#"{"message": "Hello API"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let message_id = into_json(response).await["message"]["id"].as_i64().unwrap();
+
+    // The new message is on the first page
+    let response = client
+        .clone()
+        .oneshot(
+            Request::get("/api/v1/messages/?limit=100")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let page = into_json(response).await;
+    assert!(page["data"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|message| message["id"] == message_id && message["text"] == "Hello API"));
+
+    let response = client
+        .clone()
+        .oneshot(
+            Request::delete(format!("/api/v1/messages/{message_id}/"))
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let response = client
+        .oneshot(
+            Request::get(format!("/api/v1/messages/{message_id}/"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(into_json(response).await["error"]["code"], "not_found");
+}
+
+#[tokio::test]
+async fn test_openapi() {
+    let response = client()
+        .oneshot(
+            Request::get("/api/openapi.json")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let spec = into_json(response).await;
+    let paths = spec["paths"].as_object().unwrap();
+
+    // Both the HTML views and the JSON API are documented
+    for path in ["/login/", "/create-message/", "/api/v1/messages/"] {
+        assert!(paths.contains_key(path), "{path} is missing");
+    }
+
+    assert!(spec["components"]["schemas"]["Message"].is_object());
+
+    // Nobody is told who the admins are
+    let user = &spec["components"]["schemas"]["User"]["properties"];
+    assert!(user["name"].is_object());
+    assert!(user["is_admin"].is_null());
+}
+
+#[tokio::test]
+async fn test_admin_forms_only_from_the_app() {
+    let client = client();
+    let cookie = login(&client, "Form admin").await;
+    set_admin(
+        retrieve_user_by_name("Form admin").unwrap().unwrap().id,
+        true,
+    )
+    .unwrap();
+
+    let create_webhook = |headers: &[(&'static str, &'static str)]| {
+        let mut request = Request::post("/admin/webhooks/")
+            .header(header::COOKIE, &cookie)
+            .header(header::HOST, "chat.example.com")
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded");
+
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+
+        let client = client.clone();
+        let request = request
+            .body(Body::from("url=http://127.0.0.1:9/&secret="))
+            .unwrap();
+
+        async move { client.oneshot(request).await.unwrap().status() }
+    };
+
+    // Other sites can't post forms as a signed in admin
+    let status = create_webhook(&[("Origin", "https://evil.example.com")]).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let status = create_webhook(&[
+        ("Sec-Fetch-Site", "cross-site"),
+        ("Origin", "https://chat.example.com"),
+    ])
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let response = client
+        .clone()
+        .oneshot(
+            Request::delete("/admin/scripts/1/")
+                .header(header::COOKIE, &cookie)
+                .header("Sec-Fetch-Site", "same-site")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // The app's own pages can
+    let status = create_webhook(&[
+        ("Sec-Fetch-Site", "same-origin"),
+        ("Origin", "https://chat.example.com"),
+    ])
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let status = create_webhook(&[("Origin", "https://chat.example.com")]).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    // So the other tests don't deliver to them
+    for webhook in get_webhooks().unwrap() {
+        if webhook.url == "http://127.0.0.1:9/" {
+            delete_webhook(webhook.id).unwrap();
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_admins_are_not_exposed() {
+    let client = client();
+
+    let admin = user_named("Hidden admin");
+    set_admin(admin.id, true).unwrap();
+
+    let response = client
+        .clone()
+        .oneshot(
+            Request::get(format!("/api/v1/users/{}/", admin.id))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let user = into_json(response).await;
+    assert_eq!(user["name"], "Hidden admin");
+    assert!(user.get("is_admin").is_none());
+
+    let query = format!("{{ user(id: {}) {{ isAdmin }} }}", admin.id);
+    let response = graphql(&client, None, &query).await;
+    assert!(response["errors"][0]["message"]
+        .as_str()
+        .unwrap()
+        .contains("isAdmin"));
+}
+
+/// Sends a GraphQL request, with a bearer token if one is given
+async fn graphql(client: &Router, token: Option<&str>, query: &str) -> serde_json::Value {
+    let mut request = Request::post("/graphql").header(header::CONTENT_TYPE, "application/json");
+
+    if let Some(token) = token {
+        request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+    }
+
+    let body = serde_json::json!({ "query": query }).to_string();
+    let response = client
+        .clone()
+        .oneshot(request.body(Body::from(body)).unwrap())
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    into_json(response).await
+}
+
+#[tokio::test]
+async fn test_graphql() {
+    use async_graphql::futures_util::{FutureExt, StreamExt};
+
+    let events = EventBus::new();
+    let client = client_with_events(MessageHooks::new(), events.clone());
+
+    let schema = schema(&state(MessageHooks::new(), events));
+    let mut created =
+        schema.execute_stream("subscription { messageCreated { text author { name } } }");
+
+    // Subscribing happens when the stream is first polled
+    assert!(created.next().now_or_never().is_none());
+
+    // Mutations need a logged in user
+    let response = graphql(
+        &client,
+        None,
+        r#"mutation { postMessage(text: "Hello GraphQL") { message { id } } }"#,
+    )
+    .await;
+    assert_eq!(response["errors"][0]["extensions"]["code"], "unauthorized");
+
+    // Session cookies don't count, as other sites can post with them
+    let cookie = login(&client, "GraphQL tester").await;
+    let body = serde_json::json!({
+        "query": r#"mutation { postMessage(text: "Forged") { message { id } } }"#
+    });
+    let response = client
+        .clone()
+        .oneshot(
+            Request::post("/graphql")
+                .header(header::CONTENT_TYPE, "text/plain")
+                .header(header::COOKIE, cookie)
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let response = into_json(response).await;
+    assert_eq!(response["errors"][0]["extensions"]["code"], "unauthorized");
+
+    let session = api_login(&client, "GraphQL tester").await;
+    let token = session["token"].as_str();
+
+    let response = graphql(
+        &client,
+        token,
+        r#"mutation { postMessage(text: "Hello GraphQL") { message { id } } }"#,
+    )
+    .await;
+    let message_id = response["data"]["postMessage"]["message"]["id"]
+        .as_i64()
+        .unwrap();
+
+    // The new message is sent to subscribers
+    let response = tokio::time::timeout(Duration::from_secs(5), created.next())
+        .await
+        .expect("No message was sent to the subscription")
+        .unwrap()
+        .into_result()
+        .unwrap();
+    let data = response.data.into_json().unwrap();
+    assert_eq!(data["messageCreated"]["text"], "Hello GraphQL");
+    assert_eq!(data["messageCreated"]["author"]["name"], "GraphQL tester");
+
+    let query =
+        format!("{{ message(id: {message_id}) {{ text author {{ name }} }} me {{ name }} }}");
+    let response = graphql(&client, token, &query).await;
+    assert_eq!(response["data"]["message"]["text"], "Hello GraphQL");
+    assert_eq!(response["data"]["me"]["name"], "GraphQL tester");
+
+    let query = format!("mutation {{ deleteMessage(id: {message_id}) {{ id }} }}");
+    let response = graphql(&client, token, &query).await;
+    assert_eq!(response["data"]["deleteMessage"]["id"], message_id);
+
+    let query = format!("{{ message(id: {message_id}) {{ id }} }}");
+    let response = graphql(&client, None, &query).await;
+    assert!(response["data"]["message"].is_null());
+}
+
+/// Opens the server-sent event stream at `uri`, resuming after `last_event_id` if it's given
+async fn open_event_stream(client: &Router, uri: &str, last_event_id: Option<&str>) -> Body {
+    let mut request = Request::get(uri);
+
+    if let Some(last_event_id) = last_event_id {
+        request = request.header("Last-Event-ID", last_event_id);
+    }
+
+    let response = client
+        .clone()
+        .oneshot(request.body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[header::CONTENT_TYPE],
+        "text/event-stream"
+    );
+
+    response.into_body()
+}
+
+/// Reads the next server-sent event from a stream
+async fn next_sse_event(body: &mut Body) -> String {
+    let frame = tokio::time::timeout(Duration::from_secs(5), body.frame())
+        .await
+        .expect("No event was sent")
+        .unwrap()
+        .unwrap();
+
+    String::from_utf8(frame.into_data().unwrap().to_vec()).unwrap()
+}
+
+#[tokio::test]
+async fn test_server_sent_events() {
+    let client = client();
+    let cookie = login(&client, "Streamer").await;
+
+    let mut events = open_event_stream(&client, "/events/", None).await;
+
+    post_message(&client, &cookie, "Streamed%20message").await;
+
+    let event = next_sse_event(&mut events).await;
+    assert!(event.starts_with("id: 1\n"));
+    assert!(event.contains("Streamed message"));
+
+    // Reconnecting clients are sent what they missed
+    let mut events = open_event_stream(&client, "/events/", Some("0")).await;
+
+    let event = next_sse_event(&mut events).await;
+    assert!(event.starts_with("id: 1\n"));
+    assert!(event.contains("Streamed message"));
+
+    // Pages carry on from the fragment they were loaded at
+    let mut events = open_event_stream(&client, "/events/?last_id=0", None).await;
+
+    let event = next_sse_event(&mut events).await;
+    assert!(event.starts_with("id: 1\n"));
+    assert!(event.contains("Streamed message"));
+
+    // Which is overridden by where a reconnecting stream got to
+    let mut events = open_event_stream(&client, "/events/?last_id=0", Some("5")).await;
+
+    let event = next_sse_event(&mut events).await;
+    assert!(event.contains("event: reset\n"));
+
+    // Clients which missed more than is remembered are told to load everything again
+    let mut events = open_event_stream(&client, "/events/", Some("5")).await;
+
+    let event = next_sse_event(&mut events).await;
+    assert!(event.contains("event: reset\n"));
+    assert!(event.contains("id: 1\n"));
+}
+
+async fn poll(client: &Router, query: &str) -> serde_json::Value {
+    let response = client
+        .clone()
+        .oneshot(
+            Request::get(format!("/poll/?{query}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    into_json(response).await
+}
+
+#[tokio::test]
+async fn test_long_polling() {
+    let client = client();
+    let cookie = login(&client, "Poller").await;
+
+    // Nothing happens before the timeout
+    let response = poll(&client, "timeout=1").await;
+    assert_eq!(response["fragments"], serde_json::json!([]));
+    assert_eq!(response["last_id"], 0);
+
+    // Polls are held open until something happens
+    let waiting = tokio::spawn({
+        let client = client.clone();
+        async move { poll(&client, "after=0&timeout=5").await }
+    });
+
+    post_message(&client, &cookie, "Polled%20message").await;
+
+    let response = waiting.await.unwrap();
+    assert_eq!(response["last_id"], 1);
+    assert_eq!(response["fragments"][0]["id"], 1);
+    assert!(response["fragments"][0]["html"]
+        .as_str()
+        .unwrap()
+        .contains("Polled message"));
+
+    // Fragments which have already happened are returned straight away
+    let response = poll(&client, "after=0&timeout=5").await;
+    assert_eq!(response["last_id"], 1);
+    assert!(response["fragments"][0]["html"]
+        .as_str()
+        .unwrap()
+        .contains("Polled message"));
+
+    let response = poll(&client, "after=5&timeout=5").await;
+    assert_eq!(response["reset"], true);
+    assert_eq!(response["last_id"], 1);
+}
+
+/// Serves the app on a random local port, returning its address
+async fn serve(client: &Router) -> std::net::SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let client = client.clone();
+
+    tokio::spawn(async move {
+        let client = client.into_make_service_with_connect_info::<std::net::SocketAddr>();
+        axum::serve(listener, client).await.unwrap()
+    });
+
+    address
+}
+
+type TestWebSocket =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Reads the next frame sent over a websocket, as JSON
+async fn next_websocket_frame<S>(
+    websocket: &mut tokio_tungstenite::WebSocketStream<S>,
+) -> serde_json::Value
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    use futures_util::StreamExt;
+    use tokio_tungstenite::tungstenite::Message as WebSocketMessage;
+
+    let message = tokio::time::timeout(Duration::from_secs(5), websocket.next())
+        .await
+        .expect("Nothing was sent over the websocket")
+        .unwrap()
+        .unwrap();
+
+    match message {
+        WebSocketMessage::Text(text) => serde_json::from_str(&text).unwrap(),
+        message => panic!("Expected text, got {message:?}"),
+    }
+}
+
+/// Reads the HTML from the next frame sent over a websocket
+async fn next_websocket_html<S>(websocket: &mut tokio_tungstenite::WebSocketStream<S>) -> String
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let frame = next_websocket_frame(websocket).await;
+
+    frame["html"]
+        .as_str()
+        .unwrap_or_else(|| panic!("Expected HTML, got {frame}"))
+        .to_string()
+}
+
+#[tokio::test]
+async fn test_websocket() {
+    let client = client();
+    let cookie = login(&client, "Websocket%20viewer").await;
+
+    // Websockets are served on the same port as everything else
+    let address = serve(&client).await;
+
+    // Only clients with a session from the app can connect
+    let error = tokio_tungstenite::connect_async(format!("ws://{address}/ws/"))
+        .await
+        .unwrap_err();
+
+    match error {
+        tokio_tungstenite::tungstenite::Error::Http(response) => {
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED)
+        }
+        error => panic!("Expected the handshake to be refused, got {error:?}"),
+    }
+
+    let request = websocket_request(address, "/ws/", &cookie);
+    let (mut websocket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+
+    // Clients which don't ask for a protocol are sent HTML
+    let connected = next_websocket_frame(&mut websocket).await;
+    assert_eq!(connected["version"], 1);
+    assert_eq!(connected["type"], "connected");
+    assert_eq!(connected["data"]["protocol"], "jdp-chat.v1.html");
+
+    post_message(&client, &cookie, "Sent%20over%20a%20websocket").await;
+
+    assert!(next_websocket_html(&mut websocket)
+        .await
+        .contains("Sent over a websocket"));
+}
+
+#[tokio::test]
+async fn test_slow_websockets_are_dropped() {
+    let mut websocket_handler = WebSocketHandler::new();
+
+    let address = "127.0.0.1".parse().unwrap();
+    let (_, mut fast) = websocket_handler
+        .add_websocket(None, None, Protocol::Html, address)
+        .unwrap();
+    let (_, slow) = websocket_handler
+        .add_websocket(None, None, Protocol::Html, address)
+        .unwrap();
+
+    // Broadcasting never waits for the slow websocket, which is eventually dropped
+    for count in 0..1000 {
+        websocket_handler.broadcast("count", |_, _| Some(Payload::Html(count.to_string())));
+        while fast.try_recv().is_ok() {}
+    }
+
+    assert_eq!(websocket_handler.len(), 1);
+    assert!(slow.is_closed());
+
+    websocket_handler.broadcast("count", |_, _| {
+        Some(Payload::Html("Still here".to_string()))
+    });
+
+    let frame: serde_json::Value = serde_json::from_str(&fast.recv().await.unwrap()).unwrap();
+    assert_eq!(frame["html"], "Still here");
+}
+
+/// A websocket handshake for `path`, as the session in `cookie`
+fn websocket_request(
+    address: std::net::SocketAddr,
+    path: &str,
+    cookie: &str,
+) -> tokio_tungstenite::tungstenite::handshake::client::Request {
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+    let mut request = format!("ws://{address}{path}")
+        .into_client_request()
+        .unwrap();
+    request
+        .headers_mut()
+        .insert(header::COOKIE, cookie.parse().unwrap());
+
+    request
+}
+
+/// Opens a websocket as the session in `cookie`, skipping the greeting
+async fn connect_websocket(address: std::net::SocketAddr, cookie: &str) -> TestWebSocket {
+    let request = websocket_request(address, "/ws/", cookie);
+
+    let (mut websocket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+    next_websocket_frame(&mut websocket).await;
+
+    websocket
+}
+
+#[tokio::test]
+async fn test_websocket_messages() {
+    use futures_util::SinkExt;
+    use tokio_tungstenite::tungstenite::Message as WebSocketMessage;
+
+    let client = client();
+    let address = serve(&client).await;
+
+    // Anonymous websockets can only listen
+    let cookie = anonymous_session(&client).await;
+    let mut websocket = connect_websocket(address, &cookie).await;
+
+    let request = r#"{"type": "message", "message": "Anonymous"}"#;
+    websocket
+        .send(WebSocketMessage::Text(request.to_string()))
+        .await
+        .unwrap();
+
+    let error = next_websocket_html(&mut websocket).await;
+    assert!(error.contains("id=\"message-result\""));
+    assert!(error.contains("Not logged in"));
+
+    let cookie = login(&client, "Websocket%20poster").await;
+    let mut websocket = connect_websocket(address, &cookie).await;
+
+    // Messages are validated like any other
+    let request = r#"{"type": "message", "message": ""}"#;
+    websocket
+        .send(WebSocketMessage::Text(request.to_string()))
+        .await
+        .unwrap();
+
+    assert!(next_websocket_html(&mut websocket)
+        .await
+        .contains("Invalid message"));
+
+    // htmx sends its headers along with the form's values
+    let request =
+        r#"{"type": "message", "message": "Over the socket", "HEADERS": {"HX-Request": "true"}}"#;
+    websocket
+        .send(WebSocketMessage::Text(request.to_string()))
+        .await
+        .unwrap();
+
+    let html = next_websocket_html(&mut websocket).await;
+    assert!(html.contains("Over the socket"));
+
+    let message_id = html
+        .split("id=\"message-")
+        .nth(1)
+        .and_then(|rest| rest.split('"').next())
+        .unwrap()
+        .to_string();
+
+    // IDs are sent as strings from forms
+    let request = format!(r#"{{"type": "delete", "message_id": "{message_id}"}}"#);
+    websocket
+        .send(WebSocketMessage::Text(request))
+        .await
+        .unwrap();
+
+    let html = next_websocket_html(&mut websocket).await;
+    assert!(html.contains(&format!("id=\"message-{message_id}\"")));
+    assert!(html.contains("display: none"));
+}
+
+#[tokio::test]
+async fn test_websocket_rendering_per_user() {
+    use futures_util::SinkExt;
+    use tokio_tungstenite::tungstenite::Message as WebSocketMessage;
+
+    let client = client();
+    let address = serve(&client).await;
+
+    let author_cookie = login(&client, "Renderer%20A").await;
+    let mut author = connect_websocket(address, &author_cookie).await;
+
+    // Sign in after connecting, like the page does
+    let response = client
+        .clone()
+        .oneshot(Request::get("/").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let viewer_cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+    let viewer_cookie = viewer_cookie.split(';').next().unwrap().to_string();
+
+    let mut viewer = connect_websocket(address, &viewer_cookie).await;
+
+    sign_in(&client, &viewer_cookie, "Renderer%20B").await;
+
+    let request = r#"{"type": "message", "message": "Hello @renderer b"}"#;
+    author
+        .send(WebSocketMessage::Text(request.to_string()))
+        .await
+        .unwrap();
+
+    // Only the author can delete the message, and only the viewer is mentioned by it
+    let html = next_websocket_html(&mut author).await;
+    assert!(html.contains("delete-button"));
+    assert!(!html.contains("message mention"));
+
+    let html = next_websocket_html(&mut viewer).await;
+    assert!(!html.contains("delete-button"));
+    assert!(html.contains("message mention"));
+
+    let message_id = html
+        .split("id=\"message-")
+        .nth(1)
+        .and_then(|rest| rest.split('"').next())
+        .unwrap()
+        .to_string();
+
+    // Typing is shown to everyone else
+    let request = r#"{"type": "typing"}"#;
+    viewer
+        .send(WebSocketMessage::Text(request.to_string()))
+        .await
+        .unwrap();
+
+    assert!(next_websocket_html(&mut author)
+        .await
+        .contains("Renderer B is typing..."));
+
+    let request = format!(r#"{{"type": "delete", "message_id": {message_id}}}"#);
+    author.send(WebSocketMessage::Text(request)).await.unwrap();
+
+    assert!(next_websocket_html(&mut author)
+        .await
+        .contains("display: none"));
+
+    // The viewer wasn't sent their own typing, so the deletion is next
+    assert!(next_websocket_html(&mut viewer)
+        .await
+        .contains("display: none"));
+}
+
+#[tokio::test]
+async fn test_websocket_heartbeat() {
+    use futures_util::StreamExt;
+    use tokio_tungstenite::tungstenite::Message as WebSocketMessage;
+
+    setup_database();
+
+    let mut state = state(MessageHooks::new(), EventBus::new());
+    state.websocket_handler = Box::leak(Box::new(Mutex::new(WebSocketHandler::with_heartbeat(
+        Duration::from_millis(50),
+        Duration::from_millis(300),
+    ))));
+
+    let websocket_handler = state.websocket_handler;
+    let client = app(state);
+    let address = serve(&client).await;
+
+    // Signed in up front, as the websocket has to keep reading to stay open
+    let admin = api_login(&client, "Stats admin").await;
+    let admin_id = admin["user"]["id"].as_i64().unwrap() as i32;
+    let admin_token = admin["token"].as_str().unwrap().to_string();
+    set_admin(admin_id, true).unwrap();
+
+    let user_token = api_login(&client, "Stats viewer").await["token"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let cookie = anonymous_session(&client).await;
+    let mut websocket = connect_websocket(address, &cookie).await;
+
+    // Reading answers pings, which keeps the websocket open
+    let started = tokio::time::Instant::now();
+    let mut pings = 0;
+
+    while started.elapsed() < Duration::from_millis(600) {
+        if let Ok(Some(Ok(WebSocketMessage::Ping(_)))) =
+            tokio::time::timeout(Duration::from_millis(100), websocket.next()).await
+        {
+            pings += 1;
+        }
+    }
+
+    assert!(pings > 1);
+    assert_eq!(websocket_handler.lock().unwrap().len(), 1);
+
+    // Connection counts are only for admins
+    let stats = |token: Option<String>| {
+        let client = client.clone();
+
+        async move {
+            let mut request = Request::get("/api/v1/stats/");
+
+            if let Some(token) = token {
+                request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+            }
+
+            client
+                .oneshot(request.body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+        }
+    };
+
+    assert_eq!(stats(None).await.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        stats(Some(user_token.clone())).await.status(),
+        StatusCode::FORBIDDEN
+    );
+
+    let response = stats(Some(admin_token)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let stats = into_json(response).await;
+    assert_eq!(stats["websockets"]["connections"], 1);
+    assert_eq!(stats["websockets"]["signed_in_connections"], 0);
+
+    // Going quiet gets the websocket closed
+    tokio::time::sleep(Duration::from_millis(600)).await;
+
+    assert_eq!(websocket_handler.lock().unwrap().len(), 0);
+}
+
+#[tokio::test]
+async fn test_websocket_replay() {
+    let client = client();
+    let address = serve(&client).await;
+
+    let cookie = login(&client, "Replayer").await;
+    let mut websocket = connect_websocket(address, &cookie).await;
+    let mut listener = connect_websocket(address, &cookie).await;
+
+    post_message(&client, &cookie, "Seen%20before%20reconnecting").await;
+
+    let html = next_websocket_html(&mut websocket).await;
+    let last_message_id = html
+        .split("id=\"message-")
+        .nth(1)
+        .and_then(|rest| rest.split('"').next())
+        .unwrap()
+        .to_string();
+
+    // Missed while reconnecting
+    drop(websocket);
+    post_message(&client, &cookie, "Missed%20one").await;
+    post_message(&client, &cookie, "Missed%20two").await;
+
+    while !next_websocket_html(&mut listener)
+        .await
+        .contains("Missed two")
+    {}
+
+    // Everything missed is sent in one go, straight after the greeting
+    let path = format!("/ws/?last_message_id={last_message_id}");
+    let request = websocket_request(address, &path, &cookie);
+    let (mut websocket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+    next_websocket_frame(&mut websocket).await;
+
+    let replay = next_websocket_html(&mut websocket).await;
+    assert!(!replay.contains("Seen before reconnecting"));
+    assert!(replay.find("Missed one").unwrap() < replay.find("Missed two").unwrap());
+
+    // A fresh server has nothing in memory, so loads them from the database
+    let address = serve(&client_with_hooks(MessageHooks::new())).await;
+
+    let path = format!("/ws/?last_message_id={last_message_id}");
+    let request = websocket_request(address, &path, &cookie);
+    let (mut websocket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+    next_websocket_frame(&mut websocket).await;
+
+    let replay = next_websocket_html(&mut websocket).await;
+    assert!(!replay.contains("Seen before reconnecting"));
+    assert!(replay.find("Missed one").unwrap() < replay.find("Missed two").unwrap());
+}
+
+/// Opens a TLS connection to the server, returning the DER of the certificate it was served
+async fn connect_tls(
+    address: std::net::SocketAddr,
+) -> (tokio_native_tls::TlsStream<tokio::net::TcpStream>, Vec<u8>) {
+    use tokio_native_tls::native_tls;
+
+    // The test certificates are self-signed
+    let connector = native_tls::TlsConnector::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+
+    let stream = tokio::net::TcpStream::connect(address).await.unwrap();
+    let stream = tokio_native_tls::TlsConnector::from(connector)
+        .connect("localhost", stream)
+        .await
+        .unwrap();
+
+    let certificate = stream
+        .get_ref()
+        .peer_certificate()
+        .unwrap()
+        .unwrap()
+        .to_der()
+        .unwrap();
+
+    (stream, certificate)
+}
+
+#[tokio::test]
+async fn test_tls() {
+    let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+    std::fs::create_dir(&directory).unwrap();
+
+    let certificate_path = directory.join("certificate.pem");
+    let key_path = directory.join("key.pem");
+
+    std::fs::copy("tests/certificates/first.pem", &certificate_path).unwrap();
+    std::fs::copy("tests/certificates/first.key", &key_path).unwrap();
+
+    let certificate = TlsCertificate::load(
+        certificate_path.to_str().unwrap(),
+        key_path.to_str().unwrap(),
+    )
+    .unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let client = client();
+    let cookie = anonymous_session(&client).await;
+    tokio::spawn(tls::serve(listener, client, certificate.clone()));
+
+    // Websockets connect over TLS
+    let (stream, first) = connect_tls(address).await;
+    let request = websocket_request(address, "/ws/", &cookie);
+    let (mut websocket, _) = tokio_tungstenite::client_async(request, stream)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        next_websocket_frame(&mut websocket).await["type"],
+        "connected"
+    );
+
+    // A broken certificate is ignored
+    std::fs::write(&certificate_path, "Not a certificate").unwrap();
+    assert!(certificate.reload().is_err());
+
+    let (_, certificate_served) = connect_tls(address).await;
+    assert_eq!(certificate_served, first);
+
+    // A renewed certificate is used for new connections
+    std::fs::copy("tests/certificates/second.pem", &certificate_path).unwrap();
+    std::fs::copy("tests/certificates/second.key", &key_path).unwrap();
+    certificate.reload().unwrap();
+
+    let (_, certificate_served) = connect_tls(address).await;
+    assert_ne!(certificate_served, first);
+
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[tokio::test]
+async fn test_fanout_between_instances() {
+    setup_database();
+
+    // Two instances sharing the same database
+    let mut addresses = vec![];
+
+    for _ in 0..2 {
+        let events = EventBus::new();
+
+        let fanout = Fanout::new(events.clone()).unwrap();
+        tokio::spawn(fanout.run(events.queue_local()));
+
+        let client = app(state(MessageHooks::new(), events));
+        addresses.push((serve(&client).await, client));
+    }
+
+    let (first_address, first) = &addresses[0];
+    let (second_address, _) = &addresses[1];
+
+    let cookie = login(first, "Fanned%20out").await;
+    let mut first_websocket = connect_websocket(*first_address, &cookie).await;
+    let anonymous = anonymous_session(first).await;
+    let mut second_websocket = connect_websocket(*second_address, &anonymous).await;
+
+    post_message(first, &cookie, "From%20the%20first%20instance").await;
+    post_message(first, &cookie, "Again%20from%20the%20first%20instance").await;
+
+    // Each instance sends every message exactly once, whichever instance it was posted to
+    for websocket in [&mut first_websocket, &mut second_websocket] {
+        let mut received = vec![];
+
+        while received.len() < 2 {
+            let html = next_websocket_html(websocket).await;
+
+            if html.contains("the first instance") {
+                received.push(html);
+            }
+        }
+
+        assert!(received[0].contains("From the first instance"));
+        assert!(received[1].contains("Again from the first instance"));
+    }
+}
+
+#[tokio::test]
+async fn test_websocket_json_protocol() {
+    use futures_util::SinkExt;
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::Message as WebSocketMessage;
+
+    let client = client();
+    let address = serve(&client).await;
+
+    let cookie = login(&client, "Json%20client").await;
+    let mut html_websocket = connect_websocket(address, &cookie).await;
+
+    let mut request = format!("ws://{address}/ws/").into_client_request().unwrap();
+    let headers = request.headers_mut();
+    headers.insert(header::COOKIE, cookie.parse().unwrap());
+    headers.insert(
+        header::SEC_WEBSOCKET_PROTOCOL,
+        "jdp-chat.v2.json,jdp-chat.v1.json".parse().unwrap(),
+    );
+
+    let (mut websocket, response) = tokio_tungstenite::connect_async(request).await.unwrap();
+    assert_eq!(
+        response.headers()[header::SEC_WEBSOCKET_PROTOCOL],
+        "jdp-chat.v1.json"
+    );
+
+    let connected = next_websocket_frame(&mut websocket).await;
+    assert_eq!(connected["type"], "connected");
+    assert_eq!(connected["data"]["protocol"], "jdp-chat.v1.json");
+
+    let request = r#"{"type": "message", "message": "Sent as JSON"}"#;
+    websocket
+        .send(WebSocketMessage::Text(request.to_string()))
+        .await
+        .unwrap();
+
+    let frame = next_websocket_frame(&mut websocket).await;
+    assert_eq!(frame["type"], "message.created");
+    assert_eq!(frame["data"]["message"]["text"], "Sent as JSON");
+    assert!(frame["timestamp"].as_u64().unwrap() > 0);
+    assert!(frame.get("html").is_none());
+
+    // Every websocket's copy of a broadcast has the same ID
+    let html_frame = loop {
+        let html_frame = next_websocket_frame(&mut html_websocket).await;
+
+        if html_frame["type"] == "message.created"
+            && html_frame["html"]
+                .as_str()
+                .unwrap()
+                .contains("Sent as JSON")
+        {
+            break html_frame;
+        }
+    };
+    assert_eq!(html_frame["id"], frame["id"]);
+
+    // Errors are JSON too
+    let request = r#"{"type": "message", "message": ""}"#;
+    websocket
+        .send(WebSocketMessage::Text(request.to_string()))
+        .await
+        .unwrap();
+
+    let frame = loop {
+        let frame = next_websocket_frame(&mut websocket).await;
+
+        if frame["type"] == "error" {
+            break frame;
+        }
+    };
+    assert_eq!(frame["data"]["error"], "Invalid message");
+}
+
+#[tokio::test]
+async fn test_websocket_limits() {
+    use futures_util::StreamExt;
+    use tokio_tungstenite::tungstenite::Message as WebSocketMessage;
+
+    setup_database();
+
+    let mut state = state(MessageHooks::new(), EventBus::new());
+    state.websocket_handler = Box::leak(Box::new(Mutex::new(
+        WebSocketHandler::new().with_limits(2, 1),
+    )));
+
+    let websocket_handler = state.websocket_handler;
+    let client = app(state);
+    let address = serve(&client).await;
+    let cookie = anonymous_session(&client).await;
+
+    // Pages from other sites can't connect with their visitors' cookies
+    let mut request = websocket_request(address, "/ws/", &cookie);
+    request
+        .headers_mut()
+        .insert(header::ORIGIN, "https://example.com".parse().unwrap());
+
+    match tokio_tungstenite::connect_async(request).await.unwrap_err() {
+        tokio_tungstenite::tungstenite::Error::Http(response) => {
+            assert_eq!(response.status(), StatusCode::FORBIDDEN)
+        }
+        error => panic!("Expected the handshake to be refused, got {error:?}"),
+    }
+
+    // The app's own pages can
+    let mut request = websocket_request(address, "/ws/", &cookie);
+    request
+        .headers_mut()
+        .insert(header::ORIGIN, format!("http://{address}").parse().unwrap());
+
+    let (_first, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+
+    // One address can only have so many websockets
+    let request = websocket_request(address, "/ws/", &cookie);
+    let (mut second, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+
+    match second.next().await {
+        Some(Ok(WebSocketMessage::Close(Some(close)))) => assert_eq!(u16::from(close.code), 4029),
+        message => panic!("Expected the websocket to be closed, got {message:?}"),
+    }
+
+    assert_eq!(websocket_handler.lock().unwrap().len(), 1);
+
+    // As can the whole server
+    let mut websocket_handler = WebSocketHandler::new().with_limits(2, 1);
+
+    for address in ["127.0.0.1", "127.0.0.2"] {
+        websocket_handler
+            .add_websocket(None, None, Protocol::Html, address.parse().unwrap())
+            .unwrap();
+    }
+
+    // Websockets are checked before anything is loaded for them
+    let refused = websocket_handler
+        .can_accept("127.0.0.3".parse().unwrap())
+        .unwrap_err();
+    assert_eq!(refused, ConnectionRefused::TooManyConnections);
+
+    let refused = websocket_handler
+        .add_websocket(None, None, Protocol::Html, "127.0.0.3".parse().unwrap())
+        .unwrap_err();
+    assert_eq!(refused, ConnectionRefused::TooManyConnections);
+}
+
+#[tokio::test]
+async fn test_other_connection_limits() {
+    use futures_util::StreamExt;
+    use tokio_tungstenite::tungstenite::Message as WebSocketMessage;
+
+    setup_database();
+
+    let mut state = state(MessageHooks::new(), EventBus::new());
+    state.websocket_handler = Box::leak(Box::new(Mutex::new(
+        WebSocketHandler::new().with_limits(2, 1),
+    )));
+
+    let websocket_handler = state.websocket_handler;
+    let client = app(state).layer(MockConnectInfo(TEST_ADDRESS));
+    let address = serve(&client).await;
+
+    let open_events = |origin: Option<&'static str>| {
+        let mut request = Request::get("/events/").header(header::HOST, "chat.example.com");
+
+        if let Some(origin) = origin {
+            request = request.header(header::ORIGIN, origin);
+        }
+
+        client.clone().oneshot(request.body(Body::empty()).unwrap())
+    };
+
+    let graphql_request = |origin: Option<&str>| {
+        let mut request = websocket_request(address, "/graphql/ws", "");
+        let headers = request.headers_mut();
+        headers.insert(
+            header::SEC_WEBSOCKET_PROTOCOL,
+            "graphql-transport-ws".parse().unwrap(),
+        );
+
+        if let Some(origin) = origin {
+            headers.insert(header::ORIGIN, origin.parse().unwrap());
+        }
+
+        request
+    };
+
+    // Pages from other sites can't open streams or subscriptions either
+    let response = open_events(Some("https://evil.example.com")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let request = graphql_request(Some("https://evil.example.com"));
+
+    match tokio_tungstenite::connect_async(request).await.unwrap_err() {
+        tokio_tungstenite::tungstenite::Error::Http(response) => {
+            assert_eq!(response.status(), StatusCode::FORBIDDEN)
+        }
+        error => panic!("Expected the handshake to be refused, got {error:?}"),
+    }
+
+    // Streams count against the same limits as websockets
+    let stream = open_events(Some("https://chat.example.com")).await.unwrap();
+    assert_eq!(stream.status(), StatusCode::OK);
+
+    let refused = websocket_handler
+        .lock()
+        .unwrap()
+        .can_accept(TEST_ADDRESS.ip())
+        .unwrap_err();
+    assert_eq!(refused, ConnectionRefused::TooManyFromAddress);
+
+    let response = open_events(None).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // Subscriptions are refused the same way as websockets
+    let (mut subscription, _) = tokio_tungstenite::connect_async(graphql_request(None))
+        .await
+        .unwrap();
+
+    match subscription.next().await {
+        Some(Ok(WebSocketMessage::Close(Some(close)))) => assert_eq!(u16::from(close.code), 4029),
+        message => panic!("Expected the websocket to be closed, got {message:?}"),
+    }
+
+    // Closing a stream frees its place up
+    drop(stream);
+
+    let response = open_events(None).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[test]
+fn test_allowed_origins() {
+    let headers = |origin: &str| {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, "chat.example.com".parse().unwrap());
+        headers.insert(header::ORIGIN, origin.parse().unwrap());
+        headers
+    };
+
+    let allowed_origins = Some("https://friends.example.com, https://other.example.com");
+
+    // The app's own pages are allowed whether or not other origins are
+    for allowed_origins in [None, allowed_origins] {
+        assert!(is_allowed_origin(
+            &headers("https://chat.example.com"),
+            allowed_origins
+        ));
+        assert!(!is_allowed_origin(
+            &headers("https://evil.example.com"),
+            allowed_origins
+        ));
+    }
+
+    assert!(is_allowed_origin(
+        &headers("https://other.example.com"),
+        allowed_origins
+    ));
+    assert!(!is_allowed_origin(
+        &headers("https://other.example.com"),
+        None
+    ));
+}
+
+#[tokio::test]
+async fn test_accounts() {
+    let client = client();
+    let cookie = anonymous_session(&client).await;
+
+    // Registering signs in
+    let name = format!(
+        "Account tester {}",
+        &uuid::Uuid::new_v4().simple().to_string()[..8]
+    );
+    let (body, _) = submit_login(&client, &cookie, "/register/", &name, TEST_PASSWORD).await;
+    assert!(body.contains(&format!("Welcome, {name}")));
+
+    // Nobody else can register the same name
+    let (body, _) =
+        submit_login(&client, &cookie, "/register/", &name, "some other password").await;
+    assert!(body.contains("That name is taken"));
+
+    let (body, _) = submit_login(&client, &cookie, "/register/", "Short password", "hunter2").await;
+    assert!(body.contains("Passwords need at least 8 characters"));
+
+    // Wrong passwords and unknown names fail the same way
+    let (body, _) = submit_login(&client, &cookie, "/login/", &name, "wrong password").await;
+    assert!(body.contains("Incorrect name or password"));
+
+    let unknown = uuid::Uuid::new_v4().to_string();
+    let (body, _) = submit_login(&client, &cookie, "/login/", &unknown, TEST_PASSWORD).await;
+    assert!(body.contains("Incorrect name or password"));
+
+    let (body, _) = submit_login(&client, &cookie, "/login/", &name, TEST_PASSWORD).await;
+    assert!(body.contains(&format!("Welcome, {name}")));
+
+    // Users from before passwords can't sign in until they claim their name by registering
+    let unclaimed_name = format!(
+        "Unclaimed {}",
+        &uuid::Uuid::new_v4().simple().to_string()[..8]
+    );
+    let unclaimed = create_user(&unclaimed_name).unwrap();
+
+    let (body, _) = submit_login(&client, &cookie, "/login/", &unclaimed_name, "").await;
+    assert!(body.contains("Incorrect name or password"));
+
+    // Which only a session still signed in as them can do
+    let (body, _) = submit_login(
+        &client,
+        &cookie,
+        "/register/",
+        &unclaimed_name,
+        TEST_PASSWORD,
+    )
+    .await;
+    assert!(body.contains("That name is taken"));
+
+    let (status, _) = api_register(&client, &unclaimed_name).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    let unclaimed_cookie = anonymous_session(&client).await;
+    let (_, session_id) = unclaimed_cookie.split_once('=').unwrap();
+    set_session_user(session_id, unclaimed.id).unwrap();
+
+    let (body, _) = submit_login(
+        &client,
+        &unclaimed_cookie,
+        "/register/",
+        &unclaimed_name,
+        TEST_PASSWORD,
+    )
+    .await;
+    assert!(body.contains(&format!("Welcome, {unclaimed_name}")));
+
+    let session = api_login(&client, &unclaimed_name).await;
+    assert_eq!(session["user"]["id"], unclaimed.id);
+
+    // Admins made before passwords can't be taken over either
+    let admin_name = format!(
+        "Unclaimed admin {}",
+        &uuid::Uuid::new_v4().simple().to_string()[..8]
+    );
+    let admin = create_user(&admin_name).unwrap();
+    set_admin(admin.id, true).unwrap();
+
+    let (body, _) = submit_login(&client, &cookie, "/register/", &admin_name, TEST_PASSWORD).await;
+    assert!(body.contains("That name is taken"));
+
+    // Too many failures from an address lock the name there, even with the right password. The
+    // addresses are random, as failures from them outlive the test
+    let from_anywhere = || {
+        let [a, b, c, ..] = uuid::Uuid::new_v4().into_bytes();
+        let address = std::net::SocketAddr::from(([10, a, b, c], 4000));
+
+        client_from(MessageHooks::new(), EventBus::new(), address)
+    };
+    let guesser = from_anywhere();
+
+    for _ in 0..5 {
+        let (body, _) = submit_login(&guesser, &cookie, "/login/", &name, "wrong password").await;
+        assert!(body.contains("Incorrect name or password"));
+    }
+
+    let (body, _) = submit_login(&guesser, &cookie, "/login/", &name, TEST_PASSWORD).await;
+    assert!(body.contains("Too many failed sign ins"));
+
+    let response = guesser
+        .clone()
+        .oneshot(
+            Request::post("/api/v1/sessions/")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::json!({ "name": name, "password": TEST_PASSWORD }).to_string(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(into_json(response).await["error"]["code"], "rate_limited");
+
+    // Guesses made at the same time can't get past the limit together
+    let racer = from_anywhere();
+    let guesses = (0..10).map(|_| {
+        let (racer, cookie, name) = (racer.clone(), cookie.clone(), name.clone());
+
+        tokio::spawn(async move {
+            submit_login(&racer, &cookie, "/login/", &name, "wrong password").await
+        })
+    });
+
+    let mut checked = 0;
+
+    for guess in guesses.collect::<Vec<_>>() {
+        let (body, _) = guess.await.unwrap();
+
+        if body.contains("Incorrect name or password") {
+            checked += 1;
+        }
+    }
+
+    assert_eq!(checked, 5);
+
+    // Which doesn't lock out anyone else
+    let (body, _) = submit_login(&from_anywhere(), &cookie, "/login/", &name, TEST_PASSWORD).await;
+    assert!(body.contains(&format!("Welcome, {name}")));
+}
+
+#[tokio::test]
+async fn test_logout() {
+    let client = client();
+
+    let is_signed_in = |cookie: String| {
+        let client = client.clone();
+
+        async move {
+            let response = client
+                .oneshot(
+                    Request::get("/")
+                        .header(header::COOKIE, cookie)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            into_string(response).await.contains("Welcome")
+        }
+    };
+
+    // Signing in replaces the session, so a session ID planted beforehand is useless
+    let anonymous_cookie = anonymous_session(&client).await;
+    let cookie = sign_in(&client, &anonymous_cookie, "Logout tester").await;
+
+    assert_ne!(cookie, anonymous_cookie);
+    assert!(!is_signed_in(anonymous_cookie).await);
+    assert!(is_signed_in(cookie.clone()).await);
+
+    let response = client
+        .clone()
+        .oneshot(
+            Request::post("/logout/")
+                .header(header::COOKIE, &cookie)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let new_cookie = set_cookie(&response).unwrap();
+    let body = into_string(response).await;
+    assert!(body.contains("Not signed in"));
+    assert!(body.contains("login-form"));
+
+    // Neither the old session nor the new one is signed in
+    assert_ne!(new_cookie, cookie);
+    assert!(!is_signed_in(cookie).await);
+    assert!(!is_signed_in(new_cookie).await);
+}
+
+#[tokio::test]
+async fn test_session_expiry() {
+    use crate::database::session::{
+        delete_expired_sessions, expire_session, retrieve_session, session_exists, SessionLifetime,
+    };
+
+    let client = client();
+
+    // Sessions slide forward while they're used, but never past their absolute lifetime
+    let lifetime = SessionLifetime {
+        idle: Duration::from_secs(60),
+        absolute: Duration::from_secs(600),
+    };
+    assert_eq!(lifetime.expires_at(0, 1_000), 61_000);
+    assert_eq!(lifetime.expires_at(0, 590_000), 600_000);
+
+    // Cookies last as long as the session could
+    let response = client
+        .clone()
+        .oneshot(Request::get("/").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let set_cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+    assert!(set_cookie.contains("Max-Age="));
+
+    let cookie = login(&client, "Expiry tester").await;
+    let session_id = cookie.strip_prefix("session_id=").unwrap().to_string();
+    assert!(retrieve_session(&session_id).unwrap().is_some());
+
+    // Expired sessions are treated as though they don't exist, then purged
+    expire_session(&session_id).unwrap();
+
+    assert!(retrieve_session(&session_id).unwrap().is_none());
+
+    let response = client
+        .clone()
+        .oneshot(
+            Request::get("/")
+                .header(header::COOKIE, &cookie)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert!(into_string(response).await.contains("Not signed in"));
+
+    assert!(delete_expired_sessions().unwrap() >= 1);
+
+    assert!(!session_exists(&session_id).unwrap());
+}
+
+#[tokio::test]
+async fn test_unique_names() {
+    let client = client();
+
+    let suffix = uuid::Uuid::new_v4().simple().to_string();
+    let name = format!("Nadia {}", &suffix[..8]);
+
+    // Names are tidied up before they're stored
+    let (status, session) = api_register(&client, &format!("  Nadia   {}  ", &suffix[..8])).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(session["user"]["name"], name);
+
+    // Names are the same however they're cased or typed
+    let (status, error) = api_register(&client, &name.to_uppercase()).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(error["error"]["code"], "name_taken");
+
+    let (status, _) = api_register(&client, &name.replacen('N', "\u{FF2E}", 1)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    // Case is folded rather than only lowercased
+    assert_eq!(name_key("STRASSE"), name_key("Straße"));
+    assert_eq!(name_key("ΣΟΦΟΣ"), name_key("σοφος"));
+    assert_eq!(name_skeleton("STRASSE"), name_skeleton("Straße"));
+
+    let (status, _) = api_register(&client, &format!("Straße {}", &suffix[..8])).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let (status, error) = api_register(&client, &format!("STRASSE {}", &suffix[..8])).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(error["error"]["code"], "name_taken");
+
+    // Names which only look the same are refused too
+    let (status, error) = api_register(&client, &name.replacen('a', "\u{0430}", 1)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(error["error"]["code"], "name_confusable");
+
+    let (status, _) = api_register(&client, &name.replacen('i', "1", 1)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    let (status, _) = api_register(&client, &format!("Na\u{200B}dia {}", &suffix[..8])).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, _) = api_register(&client, "Nad").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // Signing in ignores case too
+    let session = api_login(&client, &name.to_lowercase()).await;
+    assert_eq!(session["user"]["name"], name);
+
+    // The login form says why
+    let cookie = anonymous_session(&client).await;
+    let lowercase = name.to_lowercase().replace(' ', "%20");
+    let (body, _) = submit_login(&client, &cookie, "/register/", &lowercase, TEST_PASSWORD).await;
+    assert!(body.contains("That name is taken"));
+}
+
+#[tokio::test]
+async fn test_profiles() {
+    const BOUNDARY: &str = "profile-boundary";
+
+    let client = client();
+
+    // Builds a profile form, with an avatar file if there's one
+    let multipart = |fields: &[(&str, &str)], avatar: Option<&[u8]>| {
+        let mut body = Vec::new();
+
+        for (name, value) in fields {
+            body.extend(
+                format!(
+                    "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n"
+                )
+                .into_bytes(),
+            );
+        }
+
+        if let Some(avatar) = avatar {
+            body.extend(
+                format!(
+                    "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"avatar\"; filename=\"avatar\"\r\nContent-Type: application/octet-stream\r\n\r\n"
+                )
+                .into_bytes(),
+            );
+            body.extend(avatar);
+            body.extend(b"\r\n");
+        }
+
+        body.extend(format!("--{BOUNDARY}--\r\n").into_bytes());
+        body
+    };
+
+    let update = |cookie: String, user_id: i32, body: Vec<u8>| {
+        let client = client.clone();
+
+        async move {
+            let response = client
+                .oneshot(
+                    Request::post(format!("/user/{user_id}/"))
+                        .header(header::COOKIE, cookie)
+                        .header(
+                            header::CONTENT_TYPE,
+                            format!("multipart/form-data; boundary={BOUNDARY}"),
+                        )
+                        .body(Body::from(body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            (response.status(), into_string(response).await)
+        }
+    };
+
+    let get = |uri: String| {
+        let client = client.clone();
+
+        async move {
+            client
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+        }
+    };
+
+    let suffix = uuid::Uuid::new_v4().simple().to_string();
+    let name = format!("Priya {}", &suffix[..8]);
+    let cookie = anonymous_session(&client).await;
+    let cookie = sign_in(&client, &cookie, &name).await;
+    let user = retrieve_user_by_name(&name).unwrap().unwrap();
+
+    // Without an upload, the avatar is generated from the user, and is the same every time
+    let response = get(user.avatar_url.clone()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "image/svg+xml");
+    let identicon = into_string(response).await;
+    assert!(identicon.starts_with("<svg"));
+    assert_eq!(
+        identicon,
+        into_string(get(user.avatar_url.clone()).await).await
+    );
+
+    // Profiles are shown to anyone, but only their owner can change them
+    let body = into_string(get(format!("/user/{}/", user.id)).await).await;
+    assert!(body.contains(&name));
+    assert!(!body.contains("profile-form"));
+
+    let other_cookie = anonymous_session(&client).await;
+    let other_cookie = sign_in(&client, &other_cookie, &format!("Other {}", &suffix[..8])).await;
+    let (status, _) = update(
+        other_cookie,
+        user.id,
+        multipart(&[("display_name", "Impostor")], None),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // Profile text is checked before it's saved
+    let long_bio = "a".repeat(501);
+    let (status, body) = update(
+        cookie.clone(),
+        user.id,
+        multipart(&[("bio", &long_bio)], None),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains("at most 500 characters"));
+
+    // Only real images can be avatars
+    let (_, body) = update(
+        cookie.clone(),
+        user.id,
+        multipart(&[], Some(b"<script>alert(1)</script>")),
+    )
+    .await;
+    assert!(body.contains("Avatars need to be"));
+
+    let png = b"\x89PNG\r\n\x1a\nnot really the rest of a png";
+    let (status, body) = update(
+        cookie.clone(),
+        user.id,
+        multipart(
+            &[
+                ("display_name", "  Priya   P "),
+                ("pronouns", "she/her"),
+                ("bio", "Plays <b>support</b>"),
+            ],
+            Some(png),
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(!body.contains("danger"));
+    assert!(body.contains("hx-swap-oob"));
+
+    let user = retrieve_user_by_name(&name).unwrap().unwrap();
+    assert_eq!(user.display_name.as_deref(), Some("Priya P"));
+    assert_eq!(user.pronouns.as_deref(), Some("she/her"));
+    assert!(user.avatar_url.contains("?v="));
+
+    // Uploads are served as they were, and never as anything else
+    let response = get(user.avatar_url.clone()).await;
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+    assert_eq!(response.headers()["x-content-type-options"], "nosniff");
+    assert!(response.headers()[header::CACHE_CONTROL]
+        .to_str()
+        .unwrap()
+        .contains("immutable"));
+    assert_eq!(
+        response.into_body().collect().await.unwrap().to_bytes(),
+        &png[..]
+    );
+
+    // The card for popovers shows the profile, escaped
+    let body = into_string(get(format!("/user/{}/card/", user.id)).await).await;
+    assert!(body.contains("Priya P"));
+    assert!(body.contains("she/her"));
+    assert!(body.contains("&lt;b&gt;support&lt;/b&gt;"));
 
-    assert_eq!(response.status(), Status::Ok);
+    // Going back to the identicon
+    update(cookie, user.id, multipart(&[("remove_avatar", "on")], None)).await;
+    let user = retrieve_user_by_name(&name).unwrap().unwrap();
+    assert!(!user.avatar_url.contains("?v="));
+    assert_eq!(identicon, into_string(get(user.avatar_url).await).await);
 
-    let body = response.into_string();
-    assert_eq!(body.contains("A test string!"), true);
+    let response = get("/user/999999999/".to_string()).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
 }
@@ -0,0 +1,9 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The current time as a timestamp in milliseconds, which is how times are stored in the database
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards somehow")
+        .as_millis() as u64
+}
@@ -0,0 +1,151 @@
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use axum::extract::ConnectInfo;
+use axum::{Extension, Router};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::service::TowerToHyperService;
+use tokio::net::TcpListener;
+use tokio_native_tls::native_tls::{self, Identity};
+use tokio_native_tls::TlsAcceptor;
+
+/// How often the certificate files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long to wait before accepting connections again after failing to, e.g. when out of file
+/// descriptors
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+
+/// Why a certificate couldn't be loaded
+#[derive(Debug)]
+pub enum TlsError {
+    /// The certificate or key file couldn't be read
+    Io(std::io::Error),
+    /// The certificate or key wasn't valid
+    Tls(native_tls::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::Io(e) => write!(f, "Could not read certificate: {}", e),
+            TlsError::Tls(e) => write!(f, "Invalid certificate: {}", e),
+        }
+    }
+}
+
+/// A PEM certificate chain and PKCS #8 private key, which are loaded again whenever they change on
+/// disk so renewed certificates are picked up without a restart
+///
+/// Connections which are already open keep the certificate they were accepted with
+#[derive(Clone)]
+pub struct TlsCertificate {
+    certificate_path: String,
+    key_path: String,
+    acceptor: Arc<RwLock<TlsAcceptor>>,
+}
+
+fn load_acceptor(certificate_path: &str, key_path: &str) -> Result<TlsAcceptor, TlsError> {
+    let certificate = std::fs::read(certificate_path).map_err(TlsError::Io)?;
+    let key = std::fs::read(key_path).map_err(TlsError::Io)?;
+
+    let identity = Identity::from_pkcs8(&certificate, &key).map_err(TlsError::Tls)?;
+    let acceptor = native_tls::TlsAcceptor::new(identity).map_err(TlsError::Tls)?;
+
+    Ok(acceptor.into())
+}
+
+/// When the file was last changed, if it can be read
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+impl TlsCertificate {
+    pub fn load(certificate_path: &str, key_path: &str) -> Result<Self, TlsError> {
+        let acceptor = load_acceptor(certificate_path, key_path)?;
+
+        Ok(Self {
+            certificate_path: certificate_path.to_string(),
+            key_path: key_path.to_string(),
+            acceptor: Arc::new(RwLock::new(acceptor)),
+        })
+    }
+
+    /// Loads the certificate again, keeping the current one if the new one is invalid
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let acceptor = load_acceptor(&self.certificate_path, &self.key_path)?;
+        *self.acceptor.write().unwrap() = acceptor;
+
+        Ok(())
+    }
+
+    fn modified(&self) -> (Option<SystemTime>, Option<SystemTime>) {
+        (modified(&self.certificate_path), modified(&self.key_path))
+    }
+
+    /// Reloads the certificate whenever its files change
+    pub async fn watch(self) {
+        let mut last_modified = self.modified();
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let modified = self.modified();
+            if modified == last_modified {
+                continue;
+            }
+
+            // Failures are tried again next time, as the certificate and key may have been caught
+            // halfway through being replaced
+            match self.reload() {
+                Ok(()) => {
+                    println!("Reloaded TLS certificate {}", self.certificate_path);
+                    last_modified = modified;
+                }
+                Err(e) => eprintln!("Could not reload TLS certificate: {}", e),
+            }
+        }
+    }
+}
+
+/// Serves the app over HTTPS, so websockets can connect with `wss://`
+pub async fn serve(listener: TcpListener, app: Router, certificate: TlsCertificate) {
+    loop {
+        let (stream, address) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("Could not accept connection: {}", e);
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+
+        let acceptor = certificate.acceptor.read().unwrap().clone();
+        // Passed along the same way as `into_make_service_with_connect_info` does
+        let service = TowerToHyperService::new(app.clone().layer(Extension(ConnectInfo(address))));
+
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("TLS handshake with {} failed: {}", address, e);
+                    return;
+                }
+            };
+
+            // Upgrades are needed for websockets
+            let result = Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .await;
+
+            if let Err(e) = result {
+                eprintln!("Connection from {} failed: {}", address, e);
+            }
+        });
+    }
+}
@@ -0,0 +1,50 @@
+use sha2::{Digest, Sha256};
+
+/// How many cells wide and tall identicons are
+const GRID_SIZE: usize = 5;
+/// How many pixels wide and tall each cell is
+const CELL_SIZE: usize = 16;
+
+/// A symmetric pattern generated from a user's ID, as an SVG, so everyone has an avatar and it
+/// never changes
+pub fn identicon(user_id: i32) -> String {
+    let hash = Sha256::digest(format!("user:{user_id}"));
+
+    let hue = u16::from_be_bytes([hash[0], hash[1]]) % 360;
+    let colour = format!("hsl({hue}, 55%, 50%)");
+
+    // Only the left half and middle column are picked, the right half mirrors the left
+    let half = GRID_SIZE.div_ceil(2);
+    let mut cells = String::new();
+
+    for row in 0..GRID_SIZE {
+        for column in 0..half {
+            let bit = row * half + column;
+
+            if hash[2 + bit / 8] & (1 << (bit % 8)) == 0 {
+                continue;
+            }
+
+            let mirrored = GRID_SIZE - 1 - column;
+            // The middle column is its own mirror
+            let columns = match mirrored == column {
+                true => vec![column],
+                false => vec![column, mirrored],
+            };
+
+            for x in columns {
+                cells.push_str(&format!(
+                    r#"<rect x="{}" y="{}" width="{CELL_SIZE}" height="{CELL_SIZE}"/>"#,
+                    x * CELL_SIZE,
+                    row * CELL_SIZE,
+                ));
+            }
+        }
+    }
+
+    let size = GRID_SIZE * CELL_SIZE;
+
+    format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{size}" height="{size}" viewBox="0 0 {size} {size}"><rect width="{size}" height="{size}" fill="#f0f0f0"/><g fill="{colour}">{cells}</g></svg>"##
+    )
+}
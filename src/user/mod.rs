@@ -1,7 +1,37 @@
-use rusqlite::Error;
+use std::net::IpAddr;
 
+use axum::http::StatusCode;
+use rusqlite::{Error, ErrorCode};
+
+use crate::database::login_failure::{
+    delete_login_failures, forget_login_attempt, record_login_attempt,
+};
 use crate::database::session::Session;
-use crate::database::user::{retrieve_user, User};
+use crate::database::user::{
+    claim_user, create_user_with_password, retrieve_user, retrieve_user_by_skeleton,
+    retrieve_user_credentials, User,
+};
+use crate::hooks::MessageHooks;
+use crate::time::now;
+use crate::validators::{
+    validate_password, validate_user_name, ValidationError, MAX_NAME_LENGTH, MIN_NAME_LENGTH,
+    MIN_PASSWORD_LENGTH,
+};
+
+pub mod identicon;
+pub mod names;
+pub mod password;
+pub mod views;
+
+use names::{name_key, normalize_name};
+use password::{hash_password, verify_nothing, verify_password};
+
+/// How many sign ins can fail for one name from one address before it's locked there for a while
+const MAX_NAME_LOGIN_FAILURES: u32 = 5;
+/// How many sign ins can fail from one address, across every name, before it's locked for a while
+const MAX_ADDRESS_LOGIN_FAILURES: u32 = 20;
+/// How long failed sign ins count against a name or address, in milliseconds
+const LOGIN_FAILURE_WINDOW_MILLIS: u64 = 15 * 60 * 1000;
 
 pub fn get_user_from_session(session: &Session) -> Result<Option<User>, Error> {
     let user_id = session.user_id;
@@ -20,3 +50,183 @@ pub fn get_user_from_session(session: &Session) -> Result<Option<User>, Error> {
 
     Ok(user_lookup.unwrap())
 }
+
+/// Looks up the session's user, rejecting anyone who isn't an admin
+pub fn require_admin(session: &Session) -> Result<User, (StatusCode, &'static str)> {
+    match get_user_from_session(session) {
+        Ok(Some(user)) if user.is_admin => Ok(user),
+        Ok(Some(_)) => Err((StatusCode::FORBIDDEN, "Permission denied")),
+        Ok(None) => Err((StatusCode::UNAUTHORIZED, "Not logged in")),
+        Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to load user")),
+    }
+}
+
+/// Why someone couldn't register or sign in
+pub enum AccountError {
+    /// The name or password was wrong, without saying which so names can't be fished for
+    InvalidCredentials,
+    /// Too many sign ins failed recently for the name or address
+    RateLimited,
+    /// Someone else has already registered the name, or one which only differs by case or how it
+    /// was typed
+    NameTaken,
+    /// The name looks like one someone else has, and could be mistaken for it
+    NameConfusable,
+    InvalidName(ValidationError),
+    InvalidPassword(ValidationError),
+    Database(Error),
+}
+
+impl AccountError {
+    /// What to tell whoever was trying to register or sign in
+    pub fn message(&self) -> String {
+        match self {
+            Self::InvalidCredentials => "Incorrect name or password".to_string(),
+            Self::RateLimited => "Too many failed sign ins, try again later".to_string(),
+            Self::NameTaken => "That name is taken".to_string(),
+            Self::NameConfusable => "That name looks too much like one which is taken".to_string(),
+            Self::InvalidName(ValidationError::TooShort) => {
+                format!("Names need at least {MIN_NAME_LENGTH} characters")
+            }
+            Self::InvalidName(ValidationError::TooLong) => {
+                format!("Names can have at most {MAX_NAME_LENGTH} characters")
+            }
+            Self::InvalidName(ValidationError::InvalidCharacters) => {
+                "Names can't have invisible or control characters".to_string()
+            }
+            Self::InvalidPassword(ValidationError::TooShort) => {
+                format!("Passwords need at least {MIN_PASSWORD_LENGTH} characters")
+            }
+            Self::InvalidPassword(_) => "That password is too long".to_string(),
+            Self::Database(_) => "Something went wrong, try again later".to_string(),
+        }
+    }
+}
+
+impl From<Error> for AccountError {
+    fn from(error: Error) -> Self {
+        Self::Database(error)
+    }
+}
+
+/// Registers a new user with a password
+///
+/// Names are unique ignoring case and how they were typed, and can't look like anyone else's.
+/// Users from before passwords existed can only be claimed by a session already signed in as them,
+/// so they keep their messages
+pub async fn register_user(
+    name: &str,
+    password: &str,
+    session_user_id: Option<i32>,
+    message_hooks: &MessageHooks,
+) -> Result<User, AccountError> {
+    let name = normalize_name(name);
+
+    validate_user_name(&name).map_err(AccountError::InvalidName)?;
+    validate_password(password).map_err(AccountError::InvalidPassword)?;
+
+    // Hooks post under their name, so claiming it would mean posting as them
+    if message_hooks.has_hook_named(&name) {
+        return Err(AccountError::NameTaken);
+    }
+
+    match retrieve_user_credentials(&name)? {
+        Some((user, None)) if session_user_id == Some(user.id) => {
+            let password_hash = blocking_hash(password).await;
+
+            match claim_user(user.id, &password_hash)? {
+                true => Ok(user),
+                // They set a password somewhere else first
+                false => Err(AccountError::NameTaken),
+            }
+        }
+        Some(_) => Err(AccountError::NameTaken),
+        None if retrieve_user_by_skeleton(&name)?.is_some() => Err(AccountError::NameConfusable),
+        None => {
+            let password_hash = blocking_hash(password).await;
+
+            match create_user_with_password(&name, &password_hash) {
+                Ok(Some(user)) => Ok(user),
+                // Someone else registered a lookalike first
+                Ok(None) => Err(AccountError::NameConfusable),
+                // Someone else registered it first
+                Err(Error::SqliteFailure(error, _))
+                    if error.code == ErrorCode::ConstraintViolation =>
+                {
+                    Err(AccountError::NameTaken)
+                }
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+}
+
+/// Runs Argon2 on the blocking pool, as it takes long enough to hold up other requests
+async fn blocking<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> T {
+    tokio::task::spawn_blocking(work)
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
+
+async fn blocking_hash(password: &str) -> String {
+    let password = password.to_string();
+
+    blocking(move || hash_password(&password)).await
+}
+
+/// Who failed sign ins are counted against, along with how many they're allowed
+///
+/// Names are only locked for the address guessing at them, so nobody else can lock someone out of
+/// their account
+fn login_subjects(name: &str, address: IpAddr) -> Vec<(String, u32)> {
+    vec![
+        (
+            format!("name:{}@{address}", name_key(name)),
+            MAX_NAME_LOGIN_FAILURES,
+        ),
+        (format!("address:{address}"), MAX_ADDRESS_LOGIN_FAILURES),
+    ]
+}
+
+/// Checks a user's name and password, refusing to once too many sign ins have failed for the name
+/// from the address, or from the address altogether
+///
+/// Users who haven't set a password yet can't sign in until they register
+pub async fn authenticate(
+    name: &str,
+    password: &str,
+    address: IpAddr,
+) -> Result<User, AccountError> {
+    let subjects = login_subjects(name, address);
+    let since = now().saturating_sub(LOGIN_FAILURE_WINDOW_MILLIS);
+
+    // Counted as failed until the password is known to be right
+    let Some(attempt) = record_login_attempt(&subjects, since, now())? else {
+        return Err(AccountError::RateLimited);
+    };
+
+    let credentials = retrieve_user_credentials(name)?;
+    let password = password.to_string();
+
+    let user = blocking(move || match credentials {
+        Some((user, Some(password_hash))) => {
+            Some(user).filter(|_| verify_password(&password, &password_hash))
+        }
+        _ => {
+            verify_nothing(&password);
+            None
+        }
+    })
+    .await;
+
+    let Some(user) = user else {
+        return Err(AccountError::InvalidCredentials);
+    };
+
+    forget_login_attempt(&attempt)?;
+
+    // Only the name is forgiven, so an address can't keep guessing by signing in now and then
+    delete_login_failures(&subjects[0].0, since)?;
+
+    Ok(user)
+}
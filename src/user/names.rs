@@ -0,0 +1,113 @@
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+/// Characters from other scripts which look like a Latin letter, along with Latin characters which
+/// look like others, mapped to the letter they look like
+///
+/// This covers the lookalikes people actually use rather than every one in Unicode's list
+const CONFUSABLES: &[(char, char)] = &[
+    // Cyrillic
+    ('а', 'a'),
+    ('в', 'b'),
+    ('ԁ', 'd'),
+    ('е', 'e'),
+    ('ѕ', 's'),
+    ('һ', 'h'),
+    ('н', 'h'),
+    ('і', 'i'),
+    ('ј', 'j'),
+    ('к', 'k'),
+    ('ӏ', 'l'),
+    ('м', 'm'),
+    ('о', 'o'),
+    ('р', 'p'),
+    ('ԛ', 'q'),
+    ('с', 'c'),
+    ('т', 't'),
+    ('у', 'y'),
+    ('ԝ', 'w'),
+    ('х', 'x'),
+    // Greek
+    ('α', 'a'),
+    ('β', 'b'),
+    ('ε', 'e'),
+    ('η', 'n'),
+    ('ι', 'i'),
+    ('κ', 'k'),
+    ('ν', 'v'),
+    ('ο', 'o'),
+    ('ρ', 'p'),
+    ('τ', 't'),
+    ('υ', 'u'),
+    ('χ', 'x'),
+    ('γ', 'y'),
+    ('ω', 'w'),
+    // Latin and digits
+    ('ɡ', 'g'),
+    ('ı', 'i'),
+    ('0', 'o'),
+    ('1', 'l'),
+    ('|', 'l'),
+    // Lowercase i and l look alike once uppercase I is lowercased
+    ('i', 'l'),
+];
+
+/// Tidies up a name as it was typed, so it's stored the same way however it was typed
+///
+/// Compatibility characters like fullwidth letters become their usual form, and whitespace is
+/// trimmed and collapsed
+pub fn normalize_name(name: &str) -> String {
+    let name: String = name.nfkc().collect();
+
+    name.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// What names are compared by, so two names with the same key are the same name
+///
+/// Case is ignored, along with any differences `normalize_name` irons out. This is Unicode's
+/// NFKC_Casefold, so "STRASSE" and "Straße" are the same name
+pub fn name_key(name: &str) -> String {
+    fold_case(&normalize_name(name)).nfkc().collect()
+}
+
+/// Unicode's full case folding, which is lowercasing apart from a few characters
+///
+/// Expects a name which is already NFKC, which takes care of the folds of compatibility
+/// characters like "ſ" and "ﬀ"
+fn fold_case(name: &str) -> String {
+    let mut folded = String::with_capacity(name.len());
+
+    for c in name.nfd().flat_map(char::to_lowercase) {
+        match c {
+            'ß' => folded.push_str("ss"),
+            'ς' => folded.push('σ'),
+            // The iota written under Greek vowels
+            '\u{345}' => folded.push('ι'),
+            // Cherokee folds to uppercase, as lowercase was added to Unicode later
+            '\u{ab70}'..='\u{abbf}' => folded.extend(char::from_u32(c as u32 - 0xab70 + 0x13a0)),
+            '\u{13f8}'..='\u{13fd}' => folded.extend(char::from_u32(c as u32 - 8)),
+            c => folded.push(c),
+        }
+    }
+
+    folded
+}
+
+/// What a name looks like, so names which could be mistaken for each other have the same skeleton
+/// even when their keys differ, i.e. with a Cyrillic "а" in place of a Latin "a"
+pub fn name_skeleton(name: &str) -> String {
+    let skeleton: String = name_key(name)
+        .nfkd()
+        // Accents are easy to miss
+        .filter(|c| !is_combining_mark(*c))
+        .filter(|c| c.is_alphanumeric() || *c == '|')
+        .map(|c| {
+            CONFUSABLES
+                .iter()
+                .find(|(confusable, _)| *confusable == c)
+                .map_or(c, |(_, latin)| *latin)
+        })
+        .collect();
+
+    skeleton.replace("rn", "m").replace("vv", "w")
+}
@@ -0,0 +1,35 @@
+use std::sync::OnceLock;
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+
+/// Hashes a password with Argon2id and a random salt, as a PHC string
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Passwords can always be hashed with the default parameters")
+        .to_string()
+}
+
+/// Whether a password matches a hash made by `hash_password`
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    let Ok(hash) = PasswordHash::new(hash) else {
+        return false;
+    };
+
+    Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .is_ok()
+}
+
+/// Checks a password against a throwaway hash, so signing in as someone who doesn't exist takes as
+/// long as getting someone's password wrong
+pub fn verify_nothing(password: &str) {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
+    let hash = DUMMY_HASH.get_or_init(|| hash_password("not anyone's password"));
+    verify_password(password, hash);
+}
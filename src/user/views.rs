@@ -0,0 +1,300 @@
+use askama::Template;
+use axum::extract::{Multipart, Path, Query};
+use axum::http::header::{
+    CACHE_CONTROL, CONTENT_SECURITY_POLICY, CONTENT_TYPE, X_CONTENT_TYPE_OPTIONS,
+};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Redirect, Response};
+use serde::Deserialize;
+
+use super::get_user_from_session;
+use super::identicon::identicon;
+use super::names::normalize_name;
+use crate::database::user::{
+    delete_avatar, get_avatar, retrieve_user, set_avatar, update_user_profile, Avatar, User,
+};
+use crate::extractors::ExtractSession;
+use crate::template::HtmlTemplate;
+use crate::time::now;
+use crate::validators::{
+    validate_bio, validate_profile_line, ValidationError, MAX_BIO_LENGTH, MAX_DISPLAY_NAME_LENGTH,
+    MAX_PRONOUNS_LENGTH,
+};
+
+/// The biggest avatar which can be uploaded, in bytes
+const MAX_AVATAR_SIZE: usize = 256 * 1024;
+
+/// Loads a user, or responds with why they couldn't be
+fn find_user(user_id: i32) -> Result<User, (StatusCode, String)> {
+    match retrieve_user(user_id) {
+        Ok(Some(user)) => Ok(user),
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            format!("User {user_id} does not exist"),
+        )),
+        Err(e) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to load user: {e}"),
+        )),
+    }
+}
+
+#[derive(Template)]
+#[template(path = "profile.html")]
+struct ProfileTemplate {
+    user: User,
+    /// Whether the profile belongs to whoever is looking at it, so they can change it
+    is_own: bool,
+    profile_error: Option<String>,
+}
+
+///
+/// GET request to load a user's profile page
+///
+pub async fn profile_view(
+    ExtractSession(session): ExtractSession,
+    Path(user_id): Path<i32>,
+) -> Response {
+    let user = match find_user(user_id) {
+        Ok(user) => user,
+        Err(error) => return error.into_response(),
+    };
+
+    let is_own = session.user_id == Some(user.id);
+
+    HtmlTemplate(ProfileTemplate {
+        user,
+        is_own,
+        profile_error: None,
+    })
+    .into_response()
+}
+
+///
+/// GET request to go to the signed in user's own profile page
+///
+pub async fn own_profile_view(ExtractSession(session): ExtractSession) -> Redirect {
+    match session.user_id {
+        Some(user_id) => Redirect::to(&format!("/user/{user_id}/")),
+        None => Redirect::to("/"),
+    }
+}
+
+#[derive(Template)]
+#[template(path = "profile_card.html")]
+struct ProfileCardTemplate {
+    user: User,
+}
+
+///
+/// GET request to load a summary of a user's profile, i.e. for a popover
+///
+pub async fn profile_card_view(Path(user_id): Path<i32>) -> Response {
+    match find_user(user_id) {
+        Ok(user) => HtmlTemplate(ProfileCardTemplate { user }).into_response(),
+        Err(error) => error.into_response(),
+    }
+}
+
+/// A profile as submitted, before it's checked
+#[derive(Default)]
+struct ProfileForm {
+    display_name: String,
+    pronouns: String,
+    bio: String,
+    avatar: Vec<u8>,
+    remove_avatar: bool,
+}
+
+impl ProfileForm {
+    async fn read(mut multipart: Multipart) -> Result<Self, String> {
+        let mut form = Self::default();
+
+        while let Some(field) = multipart.next_field().await.map_err(|e| e.body_text())? {
+            let name = field.name().unwrap_or_default().to_string();
+
+            if name == "avatar" {
+                form.avatar = field.bytes().await.map_err(|e| e.body_text())?.to_vec();
+                continue;
+            }
+
+            let value = field.text().await.map_err(|e| e.body_text())?;
+
+            match name.as_str() {
+                "display_name" => form.display_name = value,
+                "pronouns" => form.pronouns = value,
+                "bio" => form.bio = value,
+                "remove_avatar" => form.remove_avatar = true,
+                _ => {}
+            }
+        }
+
+        Ok(form)
+    }
+}
+
+/// Empty profile fields are left unset
+fn optional(text: String) -> Option<String> {
+    Some(text).filter(|text| !text.is_empty())
+}
+
+/// Why profile text was refused, for a field with a maximum length
+fn profile_error(field: &str, max_length: usize, error: ValidationError) -> String {
+    match error {
+        ValidationError::InvalidCharacters => {
+            format!("Your {field} can't have invisible or control characters")
+        }
+        _ => format!("Your {field} can have at most {max_length} characters"),
+    }
+}
+
+/// What kind of image an upload is, going by its contents rather than what the browser says, so
+/// only images browsers can't be tricked into running are accepted
+fn image_content_type(image: &[u8]) -> Option<&'static str> {
+    match image {
+        [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => Some("image/png"),
+        [0xFF, 0xD8, 0xFF, ..] => Some("image/jpeg"),
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        _ => None,
+    }
+}
+
+/// Checks a submitted profile, and saves it if it's fine
+fn save_profile(user: &User, form: ProfileForm) -> Result<(), String> {
+    let display_name = normalize_name(&form.display_name);
+    let pronouns = form.pronouns.trim().to_string();
+    let bio = form.bio.trim().to_string();
+
+    validate_profile_line(&display_name, MAX_DISPLAY_NAME_LENGTH)
+        .map_err(|e| profile_error("display name", MAX_DISPLAY_NAME_LENGTH, e))?;
+    validate_profile_line(&pronouns, MAX_PRONOUNS_LENGTH)
+        .map_err(|e| profile_error("pronouns", MAX_PRONOUNS_LENGTH, e))?;
+    validate_bio(&bio).map_err(|e| profile_error("bio", MAX_BIO_LENGTH, e))?;
+
+    // Browsers send an empty file when none was picked
+    let avatar = match form.avatar.is_empty() {
+        true => None,
+        false if form.avatar.len() > MAX_AVATAR_SIZE => {
+            return Err(format!(
+                "Avatars can be at most {} KiB",
+                MAX_AVATAR_SIZE / 1024
+            ));
+        }
+        false => match image_content_type(&form.avatar) {
+            Some(content_type) => Some(Avatar {
+                content_type: content_type.to_string(),
+                image: form.avatar,
+            }),
+            None => return Err("Avatars need to be PNG, JPEG, GIF or WebP images".to_string()),
+        },
+    };
+
+    let saved = update_user_profile(
+        user.id,
+        optional(display_name).as_deref(),
+        optional(pronouns).as_deref(),
+        optional(bio).as_deref(),
+    )
+    .and_then(|_| match (avatar, form.remove_avatar) {
+        (Some(avatar), _) => set_avatar(user.id, &avatar, now()),
+        (None, true) => delete_avatar(user.id),
+        (None, false) => Ok(()),
+    });
+
+    saved.map_err(|e| format!("Failed to save your profile: {e}"))
+}
+
+#[derive(Template)]
+#[template(path = "profile_result.html")]
+struct ProfileResultTemplate {
+    user: User,
+    profile_error: Option<String>,
+}
+
+///
+/// POST request to change the signed in user's profile, and return the form along with the updated
+/// profile, swapped in out of band
+///
+pub async fn update_profile_view(
+    ExtractSession(session): ExtractSession,
+    Path(user_id): Path<i32>,
+    multipart: Multipart,
+) -> Response {
+    let user = match get_user_from_session(&session) {
+        Ok(Some(user)) if user.id == user_id => user,
+        Ok(Some(_)) => {
+            return (StatusCode::FORBIDDEN, "Permission denied").into_response();
+        }
+        Ok(None) => return (StatusCode::UNAUTHORIZED, "Not logged in").into_response(),
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to load user: {e}"),
+            )
+                .into_response()
+        }
+    };
+
+    let profile_error = match ProfileForm::read(multipart).await {
+        Ok(form) => save_profile(&user, form).err(),
+        Err(e) => Some(e),
+    };
+
+    // Shown as it is now, whether or not it was saved
+    let user = match find_user(user.id) {
+        Ok(user) => user,
+        Err(error) => return error.into_response(),
+    };
+
+    HtmlTemplate(ProfileResultTemplate {
+        user,
+        profile_error,
+    })
+    .into_response()
+}
+
+#[derive(Deserialize)]
+pub struct AvatarQuery {
+    /// When the avatar was uploaded, which is only there to change the URL
+    v: Option<u64>,
+}
+
+///
+/// GET request to load a user's avatar, which is an identicon unless they've uploaded one
+///
+pub async fn avatar_view(Path(user_id): Path<i32>, Query(query): Query<AvatarQuery>) -> Response {
+    let user = match find_user(user_id) {
+        Ok(user) => user,
+        Err(error) => return error.into_response(),
+    };
+
+    let (content_type, image) = match get_avatar(user.id) {
+        Ok(Some(avatar)) => (avatar.content_type, avatar.image),
+        Ok(None) => ("image/svg+xml".to_string(), identicon(user.id).into_bytes()),
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to load avatar: {e}"),
+            )
+                .into_response()
+        }
+    };
+
+    // A new upload changes the URL, so versioned avatars never change
+    let cache_control = match query.v {
+        Some(_) => "public, max-age=31536000, immutable",
+        None => "no-cache",
+    };
+
+    (
+        [
+            (CONTENT_TYPE, content_type.as_str()),
+            (CACHE_CONTROL, cache_control),
+            (X_CONTENT_TYPE_OPTIONS, "nosniff"),
+            (CONTENT_SECURITY_POLICY, "default-src 'none'"),
+        ],
+        image,
+    )
+        .into_response()
+}
@@ -1,11 +1,96 @@
 pub enum ValidationError {
     TooShort,
+    TooLong,
+    /// Control characters, or characters which can't be seen
+    InvalidCharacters,
 }
 
+/// The fewest characters a name can have
+pub const MIN_NAME_LENGTH: usize = 4;
+/// The most characters a name can have
+pub const MAX_NAME_LENGTH: usize = 32;
+
+/// The fewest characters a password can have
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+/// The most bytes a password can have, so hashing one can't take forever
+pub const MAX_PASSWORD_LENGTH: usize = 1024;
+
+/// The most characters a display name can have
+pub const MAX_DISPLAY_NAME_LENGTH: usize = 32;
+/// The most characters pronouns can have
+pub const MAX_PRONOUNS_LENGTH: usize = 24;
+/// The most characters a bio can have
+pub const MAX_BIO_LENGTH: usize = 500;
+
 pub fn validate_message(message: &str) -> Result<(), ValidationError> {
-    if message.len() < 1 {
+    if message.is_empty() {
+        return Err(ValidationError::TooShort);
+    }
+
+    Ok(())
+}
+
+/// Characters which take up no space, so can't be seen
+fn is_invisible(c: char) -> bool {
+    matches!(c, '\u{200B}'..='\u{200F}' | '\u{2060}'..='\u{2064}' | '\u{FEFF}')
+}
+
+/// Checks a name which has already been normalised
+pub fn validate_user_name(name: &str) -> Result<(), ValidationError> {
+    let length = name.chars().count();
+
+    if length < MIN_NAME_LENGTH {
         return Err(ValidationError::TooShort);
     }
 
+    if length > MAX_NAME_LENGTH {
+        return Err(ValidationError::TooLong);
+    }
+
+    // Zero width characters would let names which look the same be told apart
+    if name.chars().any(|c| c.is_control() || is_invisible(c)) {
+        return Err(ValidationError::InvalidCharacters);
+    }
+
+    Ok(())
+}
+
+pub fn validate_password(password: &str) -> Result<(), ValidationError> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(ValidationError::TooShort);
+    }
+
+    if password.len() > MAX_PASSWORD_LENGTH {
+        return Err(ValidationError::TooLong);
+    }
+
+    Ok(())
+}
+
+/// Checks a line of profile text, i.e. a display name or pronouns
+pub fn validate_profile_line(text: &str, max_length: usize) -> Result<(), ValidationError> {
+    if text.chars().count() > max_length {
+        return Err(ValidationError::TooLong);
+    }
+
+    if text.chars().any(|c| c.is_control() || is_invisible(c)) {
+        return Err(ValidationError::InvalidCharacters);
+    }
+
+    Ok(())
+}
+
+/// Checks a bio, which unlike other profile text can have several lines
+pub fn validate_bio(bio: &str) -> Result<(), ValidationError> {
+    if bio.chars().count() > MAX_BIO_LENGTH {
+        return Err(ValidationError::TooLong);
+    }
+
+    let is_allowed = |c: char| c == '\n' || c == '\r' || !(c.is_control() || is_invisible(c));
+
+    if !bio.chars().all(is_allowed) {
+        return Err(ValidationError::InvalidCharacters);
+    }
+
     Ok(())
 }
@@ -0,0 +1,162 @@
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use rusqlite::Error;
+use serde_json::json;
+use sha2::Sha256;
+
+use crate::database::webhook::{
+    claim_delivery, create_delivery, get_pending_deliveries, get_webhooks, update_delivery,
+    DeliveryStatus, WebhookDelivery,
+};
+use crate::events::Event;
+use crate::time::now;
+
+pub mod views;
+
+/// The header carrying the hex encoded HMAC-SHA256 signature of the request body
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+/// The header carrying the event name, i.e. message.created
+pub const EVENT_HEADER: &str = "X-Webhook-Event";
+/// The header carrying the delivery's ID, which stays the same across retries
+pub const DELIVERY_HEADER: &str = "X-Webhook-Delivery";
+
+/// How many times a delivery is attempted before it is marked as failed
+pub const MAX_ATTEMPTS: u32 = 6;
+/// The delay before the first retry, which doubles for each attempt after that
+const BASE_BACKOFF_MILLIS: u64 = 1000;
+/// How long the worker waits for a receiver to respond
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the worker checks for due deliveries
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a claimed delivery is left to its worker before another may attempt it, in case the
+/// worker died partway through
+const CLAIM_LEASE: Duration = Duration::from_secs(60);
+
+/// The JSON body sent to receivers, or nothing if webhooks aren't sent for the event
+fn payload(event: &Event, timestamp: u64) -> Option<String> {
+    let message = match event {
+        Event::MessageCreated(message) | Event::MessageDeleted(message) => message,
+        _ => return None,
+    };
+
+    let payload = json!({
+        "event": event.name(),
+        "timestamp": timestamp,
+        "message": message,
+    });
+
+    Some(payload.to_string())
+}
+
+/// Signs a payload with a webhook's secret, returning the hex encoded HMAC-SHA256
+pub fn sign(secret: &str, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(payload.as_bytes());
+
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// How long to wait before retrying a delivery which has failed `attempts` times
+pub fn backoff(attempts: u32) -> Duration {
+    let exponent = attempts.saturating_sub(1).min(16);
+
+    Duration::from_millis(BASE_BACKOFF_MILLIS * 2u64.pow(exponent))
+}
+
+/// Queues an event for every registered webhook
+///
+/// Deliveries are made by the background worker so a slow receiver never holds up a request
+pub fn dispatch_event(event: &Event) -> Result<(), Error> {
+    let now = now();
+
+    let Some(payload) = payload(event, now) else {
+        return Ok(());
+    };
+
+    for webhook in get_webhooks()? {
+        create_delivery(webhook.id, event.name(), &payload, now)?;
+    }
+
+    Ok(())
+}
+
+/// Sends a single delivery, returning the receiver's status code if it responded at all
+async fn send(client: &reqwest::Client, delivery: &WebhookDelivery) -> Result<u16, String> {
+    let response = client
+        .post(&delivery.url)
+        .timeout(REQUEST_TIMEOUT)
+        .header("Content-Type", "application/json")
+        .header(EVENT_HEADER, &delivery.event)
+        .header(DELIVERY_HEADER, delivery.id)
+        .header(
+            SIGNATURE_HEADER,
+            format!("sha256={}", sign(&delivery.secret, &delivery.payload)),
+        )
+        .body(delivery.payload.clone())
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(response.status().as_u16())
+}
+
+/// Attempts every delivery which is currently due, returning how many were attempted
+///
+/// Failed deliveries are rescheduled with an exponential backoff until they run out of attempts.
+/// Each delivery is claimed before it's sent, so when several instances share the database only
+/// one of them sends it
+pub async fn deliver_pending(client: &reqwest::Client) -> Result<usize, Error> {
+    let mut count = 0;
+
+    for mut delivery in get_pending_deliveries(now())? {
+        let claimed_at = now();
+        let lease_until = claimed_at + CLAIM_LEASE.as_millis() as u64;
+
+        if !claim_delivery(delivery.id, claimed_at, lease_until)? {
+            continue;
+        }
+
+        count += 1;
+        delivery.attempts += 1;
+
+        match send(client, &delivery).await {
+            Ok(status_code) => {
+                delivery.last_status_code = Some(status_code);
+                delivery.last_error = None;
+
+                if (200..300).contains(&status_code) {
+                    delivery.status = DeliveryStatus::Delivered;
+                }
+            }
+            Err(e) => {
+                delivery.last_status_code = None;
+                delivery.last_error = Some(e);
+            }
+        }
+
+        if delivery.status == DeliveryStatus::Pending && delivery.attempts >= MAX_ATTEMPTS {
+            delivery.status = DeliveryStatus::Failed;
+        }
+
+        let next_attempt_at = now() + backoff(delivery.attempts).as_millis() as u64;
+
+        update_delivery(&delivery, next_attempt_at)?;
+    }
+
+    Ok(count)
+}
+
+/// Runs forever, sending queued deliveries to their webhooks
+pub async fn run_delivery_worker() {
+    let client = reqwest::Client::new();
+
+    loop {
+        if let Err(e) = deliver_pending(&client).await {
+            eprintln!("Webhook delivery error: {}", e);
+        }
+
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
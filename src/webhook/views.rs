@@ -0,0 +1,133 @@
+use askama::Template;
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Form;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::database::webhook::{
+    create_webhook, delete_webhook, get_recent_deliveries, get_webhooks, Webhook, WebhookDelivery,
+};
+use crate::extractors::{ExtractSession, SameOrigin};
+use crate::template::HtmlTemplate;
+use crate::time::now;
+use crate::user::require_admin;
+
+/// How many deliveries are shown in the delivery log
+const DELIVERY_LOG_LENGTH: u32 = 50;
+
+#[derive(Template)]
+#[template(path = "webhooks.html")]
+struct WebhooksTemplate {
+    webhooks: Vec<Webhook>,
+    show_secret: bool,
+    deliveries: Vec<WebhookDelivery>,
+}
+
+///
+/// GET request to load the webhook admin page
+///
+pub async fn webhooks_view(ExtractSession(session): ExtractSession) -> Response {
+    if let Err(e) = require_admin(&session) {
+        return e.into_response();
+    }
+
+    let webhooks = get_webhooks();
+    let deliveries = get_recent_deliveries(DELIVERY_LOG_LENGTH);
+
+    match (webhooks, deliveries) {
+        (Ok(webhooks), Ok(deliveries)) => HtmlTemplate(WebhooksTemplate {
+            webhooks,
+            show_secret: false,
+            deliveries,
+        })
+        .into_response(),
+        (Err(e), _) | (_, Err(e)) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to load webhooks: {e}"),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(Template)]
+#[template(path = "webhook.html")]
+struct WebhookTemplate {
+    webhook: Webhook,
+    show_secret: bool,
+}
+
+#[derive(Deserialize)]
+pub struct CreateWebhookRequest {
+    url: String,
+    secret: String,
+}
+
+///
+/// POST request to register a new webhook, and return it as a table row
+///
+/// Only from the app's own pages, so other sites can't register webhooks as a signed in admin
+///
+pub async fn create_webhook_view(
+    _: SameOrigin,
+    ExtractSession(session): ExtractSession,
+    Form(request): Form<CreateWebhookRequest>,
+) -> Response {
+    if let Err(e) = require_admin(&session) {
+        return e.into_response();
+    }
+
+    if !request.url.starts_with("http://") && !request.url.starts_with("https://") {
+        return (StatusCode::BAD_REQUEST, "Invalid URL").into_response();
+    }
+
+    // Generate a secret if the admin didn't provide one
+    let secret = match request.secret.trim() {
+        "" => Uuid::new_v4().simple().to_string(),
+        secret => secret.to_string(),
+    };
+
+    match create_webhook(&request.url, &secret, now()) {
+        Ok(webhook) => (
+            StatusCode::CREATED,
+            HtmlTemplate(WebhookTemplate {
+                webhook,
+                show_secret: true,
+            }),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error creating webhook: {e}"),
+        )
+            .into_response(),
+    }
+}
+
+///
+/// DELETE request to remove a webhook
+///
+pub async fn delete_webhook_view(
+    _: SameOrigin,
+    ExtractSession(session): ExtractSession,
+    Path(webhook_id): Path<i32>,
+) -> Response {
+    if let Err(e) = require_admin(&session) {
+        return e.into_response();
+    }
+
+    match delete_webhook(webhook_id) {
+        Ok(0) => (
+            StatusCode::NOT_FOUND,
+            format!("Webhook {webhook_id} does not exist"),
+        )
+            .into_response(),
+        Ok(_) => StatusCode::OK.into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error deleting webhook: {e}"),
+        )
+            .into_response(),
+    }
+}
@@ -1,41 +1,873 @@
-use std::net::TcpStream;
-use tungstenite::{Error, Message, WebSocket};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use askama::Template;
+use axum::extract::ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{ConnectInfo, Query, State};
+use axum::http::header::{HOST, ORIGIN};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum_extra::extract::CookieJar;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::json;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::watch;
+use tokio::task::spawn_blocking;
+use tokio::time::Instant;
+use utoipa::ToSchema;
+
+use crate::chat::{post_message, remove_message, DeleteMessageError, PostMessageError};
+use crate::database::message::{self, get_messages_after};
+use crate::database::session::retrieve_session;
+use crate::database::user::User;
+use crate::envelope::{frame, frame_id, Envelope, Payload, Protocol, PROTOCOL_VERSION};
+use crate::events::Event;
+use crate::replay::{ReplayBuffer, MAX_REPLAY};
+use crate::time::now;
+use crate::user::get_user_from_session;
+use crate::{render_event_for, AppState, CLIENT_IP_HEADER, WEBSOCKET_ALLOWED_ORIGINS};
+
+/// How many messages can be waiting to be written to a websocket before it's considered too slow
+/// to keep up, and is disconnected
+const WEBSOCKET_BUFFER: usize = 64;
+
+/// The close code sent to websockets which couldn't keep up, telling them to try again later
+const CLOSE_TRY_AGAIN_LATER: u16 = 1013;
+
+/// The first frame sent to every websocket, saying which protocol was agreed on
+const CONNECTED_FRAME: &str = "connected";
+/// Sent to just the websocket whose request failed
+const ERROR_FRAME: &str = "error";
+/// Everything a reconnecting websocket missed, sent in one go
+const REPLAY_FRAME: &str = "messages.replayed";
+
+/// The close code sent when the server has as many websockets as it can take
+const CLOSE_TOO_MANY_CONNECTIONS: u16 = 1013;
+/// The close code sent when an address has as many websockets open as it's allowed, which htmx
+/// doesn't reconnect after
+const CLOSE_TOO_MANY_FROM_ADDRESS: u16 = 4029;
+
+/// The most websockets which can be connected at once
+const MAX_CONNECTIONS: usize = 1000;
+/// The most websockets which can be connected from one IP address at once
+const MAX_CONNECTIONS_PER_ADDRESS: usize = 10;
+
+/// How often each websocket can tell everyone its user is typing
+const TYPING_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How often websockets are pinged to check they're still there
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// How long a websocket can go without sending anything, including pongs, before it's closed
+const IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// A connected websocket
+struct Client {
+    /// The queue of messages to write
+    sender: Sender<String>,
+    /// The session the websocket connected with
+    session_id: Option<String>,
+    /// Who broadcasts are rendered for and requests are made by, or nobody if the session isn't
+    /// signed in, which the websocket's reader watches for changes
+    user: watch::Sender<Option<User>>,
+    /// Whether the websocket is sent HTML or JSON
+    protocol: Protocol,
+    /// Where the websocket connected from
+    address: IpAddr,
+}
+
+/// Why a websocket wasn't added
+#[derive(Debug, PartialEq)]
+pub enum ConnectionRefused {
+    /// The server has as many websockets as it can take
+    TooManyConnections,
+    /// The websocket's address has as many websockets as it's allowed
+    TooManyFromAddress,
+}
+
+impl ConnectionRefused {
+    fn reason(&self) -> &'static str {
+        match self {
+            Self::TooManyConnections => "Too many connections",
+            Self::TooManyFromAddress => "Too many connections from your address",
+        }
+    }
+
+    /// Tells the websocket why it's being closed
+    fn close_frame(&self) -> CloseFrame<'static> {
+        let code = match self {
+            Self::TooManyConnections => CLOSE_TOO_MANY_CONNECTIONS,
+            Self::TooManyFromAddress => CLOSE_TOO_MANY_FROM_ADDRESS,
+        };
+
+        CloseFrame {
+            code,
+            reason: self.reason().into(),
+        }
+    }
+
+    /// Tells connections which aren't websockets why they were refused
+    fn status(&self) -> (StatusCode, &'static str) {
+        let status = match self {
+            Self::TooManyConnections => StatusCode::SERVICE_UNAVAILABLE,
+            Self::TooManyFromAddress => StatusCode::TOO_MANY_REQUESTS,
+        };
+
+        (status, self.reason())
+    }
+}
+
+/// A connection other than a chat websocket, like a server-sent event stream, which counts against
+/// the same limits until it's dropped
+pub struct ConnectionSlot {
+    websocket_handler: &'static Mutex<WebSocketHandler>,
+    address: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut websocket_handler = self.websocket_handler.lock().unwrap();
+
+        if let Some(count) = websocket_handler.other_connections.get_mut(&self.address) {
+            *count -= 1;
+
+            if *count == 0 {
+                websocket_handler.other_connections.remove(&self.address);
+            }
+        }
+    }
+}
+
+/// How many websockets are connected, for monitoring
+#[derive(Serialize, ToSchema)]
+pub struct WebSocketStats {
+    pub connections: usize,
+    /// Connections whose session is signed in
+    pub signed_in_connections: usize,
+    /// Different users with at least one connection
+    pub users: usize,
+}
 
 pub struct WebSocketHandler {
-    websockets: Vec<WebSocket<TcpStream>>,
+    next_id: u64,
+    websockets: HashMap<u64, Client>,
+    ping_interval: Duration,
+    idle_timeout: Duration,
+    max_connections: usize,
+    max_connections_per_address: usize,
+    /// How many connections other than websockets each address has, which count against the same
+    /// limits
+    other_connections: HashMap<IpAddr, usize>,
+    /// Recently broadcast messages, for websockets which reconnect
+    replay: ReplayBuffer,
 }
 
 impl WebSocketHandler {
     pub fn new() -> Self {
+        Self::with_heartbeat(PING_INTERVAL, IDLE_TIMEOUT)
+            .with_limits(MAX_CONNECTIONS, MAX_CONNECTIONS_PER_ADDRESS)
+    }
+
+    /// Pings websockets every `ping_interval`, closing any which are quiet for `idle_timeout`
+    ///
+    /// Any number of websockets are accepted, unless limited with `with_limits`
+    pub fn with_heartbeat(ping_interval: Duration, idle_timeout: Duration) -> Self {
         Self {
-            websockets: Vec::new(),
+            next_id: 0,
+            websockets: HashMap::new(),
+            ping_interval,
+            idle_timeout,
+            max_connections: usize::MAX,
+            max_connections_per_address: usize::MAX,
+            other_connections: HashMap::new(),
+            replay: ReplayBuffer::new(),
         }
     }
 
-    pub fn add_websocket(&mut self, websocket: WebSocket<TcpStream>) {
-        self.websockets.push(websocket);
+    /// Refuses websockets beyond `max_connections` in total, or `max_connections_per_address` from
+    /// one IP address
+    pub fn with_limits(
+        mut self,
+        max_connections: usize,
+        max_connections_per_address: usize,
+    ) -> Self {
+        self.max_connections = max_connections;
+        self.max_connections_per_address = max_connections_per_address;
+        self
     }
 
-    pub fn broadcast(&mut self, message: &str) -> Result<(), Error> {
-        let mut unhealthy_indexes = Vec::<usize>::new();
+    /// Remembers a message about to be broadcast, so it can be replayed to websockets which missed
+    /// it
+    pub fn remember(&mut self, message: message::Message) {
+        self.replay.remember(message);
+    }
 
-        for (index, websocket) in self.websockets.iter_mut().enumerate() {
-            if let Err(e) = websocket.send(Message::Text(message.to_string())) {
-                unhealthy_indexes.push(index);
-                eprintln!("Error sending message: {}", e);
-            }
+    /// Stops replaying a deleted message
+    pub fn forget(&mut self, message_id: i32) {
+        self.replay.forget(message_id);
+    }
+
+    /// Whether another connection from `address` would be accepted right now
+    pub fn can_accept(&self, address: IpAddr) -> Result<(), ConnectionRefused> {
+        let others: usize = self.other_connections.values().sum();
+
+        if self.websockets.len() + others >= self.max_connections {
+            return Err(ConnectionRefused::TooManyConnections);
         }
 
-        for index in unhealthy_indexes.iter().rev() {
-            println!(
-                "Removing websocket at index {} due to it being unhealthy",
-                index
-            );
+        let from_address = self
+            .websockets
+            .values()
+            .filter(|client| client.address == address)
+            .count()
+            + self.other_connections.get(&address).unwrap_or(&0);
 
-            // Remove all websockets that failed to be written to
-            self.websockets.remove(*index);
+        if from_address >= self.max_connections_per_address {
+            return Err(ConnectionRefused::TooManyFromAddress);
         }
 
         Ok(())
     }
+
+    /// Adds a websocket, returning its ID and the messages it should be sent, unless there are
+    /// already too many
+    pub fn add_websocket(
+        &mut self,
+        session_id: Option<String>,
+        user: Option<User>,
+        protocol: Protocol,
+        address: IpAddr,
+    ) -> Result<(u64, Receiver<String>), ConnectionRefused> {
+        self.can_accept(address)?;
+
+        let (sender, receiver) = mpsc::channel(WEBSOCKET_BUFFER);
+        let (user, _) = watch::channel(user);
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.websockets.insert(
+            id,
+            Client {
+                sender,
+                session_id,
+                user,
+                protocol,
+                address,
+            },
+        );
+
+        Ok((id, receiver))
+    }
+
+    /// Counts a connection which isn't a chat websocket against the limits until the slot is
+    /// dropped, unless there are already too many
+    pub fn add_other_connection(
+        websocket_handler: &'static Mutex<Self>,
+        address: IpAddr,
+    ) -> Result<ConnectionSlot, ConnectionRefused> {
+        let mut locked = websocket_handler.lock().unwrap();
+
+        locked.can_accept(address)?;
+        *locked.other_connections.entry(address).or_default() += 1;
+
+        Ok(ConnectionSlot {
+            websocket_handler,
+            address,
+        })
+    }
+
+    pub fn remove_websocket(&mut self, id: u64) {
+        self.websockets.remove(&id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.websockets.len()
+    }
+
+    pub fn stats(&self) -> WebSocketStats {
+        let users: HashSet<i32> = self
+            .websockets
+            .values()
+            .filter_map(|client| client.user.borrow().as_ref().map(|user| user.id))
+            .collect();
+
+        WebSocketStats {
+            connections: self.websockets.len(),
+            signed_in_connections: self
+                .websockets
+                .values()
+                .filter(|client| client.user.borrow().is_some())
+                .count(),
+            users: users.len(),
+        }
+    }
+
+    /// Removes websockets whose connection has already finished
+    pub fn reap(&mut self) -> usize {
+        let before = self.websockets.len();
+        self.websockets
+            .retain(|_, client| !client.sender.is_closed());
+
+        before - self.websockets.len()
+    }
+
+    /// Moves every websocket connected with a session over to the session which replaced it, by
+    /// signing in or out, and renders broadcasts for its user from now on
+    pub fn move_session(
+        &mut self,
+        previous_session_id: &str,
+        session_id: &str,
+        user: Option<User>,
+    ) {
+        for client in self.websockets.values_mut() {
+            if client.session_id.as_deref() == Some(previous_session_id) {
+                client.session_id = Some(session_id.to_string());
+                client.user.send_replace(user.clone());
+            }
+        }
+    }
+
+    /// Watches who a websocket is acting for, which changes as it signs in and out
+    fn watch_user(&self, id: u64) -> Option<watch::Receiver<Option<User>>> {
+        Some(self.websockets.get(&id)?.user.subscribe())
+    }
+
+    /// Queues a frame for one websocket, under the same rules as `broadcast`
+    pub fn send(&mut self, id: u64, kind: &str, payload: &Payload) {
+        let Some(client) = self.websockets.get(&id) else {
+            return;
+        };
+
+        if !Self::queue(id, &client.sender, frame(kind, payload)) {
+            self.websockets.remove(&id);
+        }
+    }
+
+    /// Queues a frame for every websocket without waiting for any of them, rendered for each
+    /// websocket's protocol and user
+    ///
+    /// Everyone with the same protocol and user, or not signed in, shares one rendering. Websockets
+    /// are skipped when nothing is rendered for them, and disconnected when their queue is full, so
+    /// one slow client can't hold up everyone
+    pub fn broadcast(
+        &mut self,
+        kind: &str,
+        mut render: impl FnMut(Protocol, Option<&User>) -> Option<Payload>,
+    ) {
+        let frame_id = frame_id();
+        let timestamp = now();
+        let mut rendered = HashMap::<(Protocol, Option<i32>), Option<String>>::new();
+
+        self.websockets.retain(|id, client| {
+            let viewer = client.user.borrow();
+            let viewer = viewer.as_ref();
+            let message = rendered
+                .entry((client.protocol, viewer.map(|user| user.id)))
+                .or_insert_with(|| {
+                    render(client.protocol, viewer)
+                        .map(|payload| Envelope::new(kind, &frame_id, timestamp, &payload).encode())
+                });
+
+            match message {
+                Some(message) => Self::queue(*id, &client.sender, message.clone()),
+                None => true,
+            }
+        });
+    }
+
+    /// Queues a message, returning whether the websocket should be kept
+    fn queue(id: u64, sender: &Sender<String>, message: String) -> bool {
+        match sender.try_send(message) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                println!("Removing websocket {} due to it falling behind", id);
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+/// Accepts message IDs as numbers or strings, as htmx sends every form value as a string
+fn deserialize_id<'de, D>(deserializer: D) -> Result<i32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Id {
+        Number(i32),
+        Text(String),
+    }
+
+    match Id::deserialize(deserializer)? {
+        Id::Number(id) => Ok(id),
+        Id::Text(id) => id.trim().parse().map_err(serde::de::Error::custom),
+    }
+}
+
+/// Something a client asked for over its websocket, i.e. `{"type": "message", "message": "Hi"}`
+///
+/// Anything else sent along with it, like the headers htmx adds, is ignored
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Message {
+        message: String,
+    },
+    Delete {
+        #[serde(deserialize_with = "deserialize_id")]
+        message_id: i32,
+    },
+    Typing,
+}
+
+#[derive(Template)]
+#[template(path = "websocket_error.html")]
+struct WebSocketErrorTemplate {
+    error: String,
+}
+
+/// A websocket's connection to the chat, acting on behalf of whoever its session belongs to
+struct Connection {
+    id: u64,
+    state: AppState,
+    protocol: Protocol,
+    /// Kept up to date by the handler as the session signs in and out, so it's never looked up
+    user: watch::Receiver<Option<User>>,
+    last_typed: Option<Instant>,
+}
+
+impl Connection {
+    /// The session's user, who can change after connecting
+    fn user(&self) -> Option<User> {
+        self.user.borrow().clone()
+    }
+
+    /// Sends an error back to just this websocket
+    fn send_error(&self, error: String) {
+        let payload = match self.protocol {
+            Protocol::Html => match (WebSocketErrorTemplate { error }).render() {
+                Ok(html) => Payload::Html(html),
+                Err(e) => {
+                    eprintln!("Failed to render websocket error: {}", e);
+                    return;
+                }
+            },
+            Protocol::Json => Payload::Data(json!({ "error": error })),
+        };
+
+        self.state
+            .websocket_handler
+            .lock()
+            .unwrap()
+            .send(self.id, ERROR_FRAME, &payload);
+    }
+
+    /// Acts on a text frame from the client, with the same validation and permissions as the
+    /// HTTP views
+    ///
+    /// Successes aren't replied to, as the resulting events are broadcast to everyone
+    async fn handle(&mut self, text: &str) {
+        let client_message = match serde_json::from_str::<ClientMessage>(text) {
+            Ok(client_message) => client_message,
+            Err(e) => return self.send_error(format!("Invalid request: {e}")),
+        };
+
+        let Some(user) = self.user() else {
+            return self.send_error("Not logged in".to_string());
+        };
+
+        match client_message {
+            ClientMessage::Message { message } => {
+                let posted = post_message(
+                    &message,
+                    &user,
+                    self.state.message_hooks,
+                    &self.state.events,
+                )
+                .await;
+
+                let error = match posted {
+                    Ok(_) => return,
+                    Err(PostMessageError::Invalid) => "Invalid message".to_string(),
+                    Err(PostMessageError::Rejected(reason)) => reason,
+                    Err(PostMessageError::Database(e)) => format!("Error creating message: {e}"),
+                };
+
+                self.send_error(error);
+            }
+            ClientMessage::Delete { message_id } => {
+                let error = match remove_message(message_id, &user, &self.state.events).await {
+                    Ok(_) => return,
+                    Err(DeleteMessageError::NotFound) => {
+                        format!("Message {message_id} does not exist")
+                    }
+                    Err(DeleteMessageError::Forbidden) => "Permission denied".to_string(),
+                    Err(DeleteMessageError::Database(e)) => format!("Error deleting message: {e}"),
+                };
+
+                self.send_error(error);
+            }
+            ClientMessage::Typing => {
+                let now = Instant::now();
+
+                if self
+                    .last_typed
+                    .is_some_and(|last_typed| now - last_typed < TYPING_INTERVAL)
+                {
+                    return;
+                }
+
+                self.last_typed = Some(now);
+                self.state.events.publish(Event::UserTyping {
+                    user_id: user.id,
+                    name: user.name,
+                });
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct WebSocketQuery {
+    /// The last message a reconnecting client was sent, so it can be sent what it missed
+    last_message_id: Option<i32>,
+}
+
+/// Whether a browser is allowed to connect from the page it's on
+///
+/// Only pages served by the app itself, or from `allowed_origins`, a comma separated list like
+/// `WEBSOCKET_ALLOWED_ORIGINS`, are allowed, so other sites can't connect with their visitors'
+/// cookies. Clients other than browsers don't send an origin, and are allowed
+pub fn is_allowed_origin(headers: &HeaderMap, allowed_origins: Option<&str>) -> bool {
+    let Some(origin) = headers.get(ORIGIN) else {
+        return true;
+    };
+
+    let Ok(origin) = origin.to_str() else {
+        return false;
+    };
+
+    let host = headers.get(HOST).and_then(|host| host.to_str().ok());
+
+    if host.is_some() && origin.split_once("://").map(|(_, origin_host)| origin_host) == host {
+        return true;
+    }
+
+    allowed_origins.is_some_and(|allowed_origins| {
+        allowed_origins
+            .split(',')
+            .any(|allowed_origin| allowed_origin.trim() == origin)
+    })
+}
+
+/// Lets in a long-lived HTTP response, like server-sent events, on the same terms as websockets
+///
+/// Browsers are only allowed from allowed origins, and the response counts against the same limits
+/// until the slot is dropped. Refusals are responses rather than close frames
+pub fn accept_connection(
+    websocket_handler: &'static Mutex<WebSocketHandler>,
+    headers: &HeaderMap,
+    peer: SocketAddr,
+) -> Result<ConnectionSlot, (StatusCode, &'static str)> {
+    if !is_allowed_origin(headers, WEBSOCKET_ALLOWED_ORIGINS) {
+        return Err((StatusCode::FORBIDDEN, "Origin not allowed"));
+    }
+
+    let address = client_address(headers, peer);
+
+    WebSocketHandler::add_other_connection(websocket_handler, address).map_err(|refused| {
+        println!("Refused connection from {}: {:?}", address, refused);
+        refused.status()
+    })
+}
+
+/// The IP address a client connected from, which is the proxy's address unless `CLIENT_IP_HEADER`
+/// says which header it passes the client's address along in
+pub fn client_address(headers: &HeaderMap, peer: SocketAddr) -> IpAddr {
+    CLIENT_IP_HEADER
+        .and_then(|name| headers.get(name))
+        .and_then(|value| value.to_str().ok())
+        // Proxies add the address they connected from to the end of any others
+        .and_then(|value| value.rsplit(',').next())
+        .and_then(|address| address.trim().parse().ok())
+        .unwrap_or(peer.ip())
+}
+
+/// Loads messages created after `last_message_id` which might be too old to be kept in memory
+async fn load_missed_messages(
+    websocket_handler: &Mutex<WebSocketHandler>,
+    last_message_id: Option<i32>,
+) -> Vec<message::Message> {
+    let Some(last_message_id) = last_message_id else {
+        return vec![];
+    };
+
+    if !websocket_handler
+        .lock()
+        .unwrap()
+        .replay
+        .needs_database(last_message_id)
+    {
+        return vec![];
+    }
+
+    let loaded = spawn_blocking(move || get_messages_after(last_message_id, MAX_REPLAY as u32))
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+
+    match loaded {
+        Ok(messages) => messages,
+        Err(e) => {
+            eprintln!("Failed to load missed messages: {}", e);
+            vec![]
+        }
+    }
+}
+
+///
+/// GET request to upgrade to a websocket which is sent every broadcast
+///
+/// Signed in clients can also post and delete messages, and say they're typing, over the websocket
+///
+/// Clients ask for HTML or JSON frames with the `Sec-WebSocket-Protocol` header, and are sent HTML
+/// if they don't ask for either
+///
+/// Only clients with a session from the app are accepted, and browsers only from allowed origins
+///
+pub async fn websocket_view(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(query): Query<WebSocketQuery>,
+    headers: HeaderMap,
+    jar: CookieJar,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    if !is_allowed_origin(&headers, WEBSOCKET_ALLOWED_ORIGINS) {
+        return (StatusCode::FORBIDDEN, "Origin not allowed").into_response();
+    }
+
+    let session_id = jar
+        .get("session_id")
+        .map(|cookie| cookie.value().to_string());
+
+    let session = match session_id.as_deref().map(retrieve_session) {
+        Some(Ok(Some(session))) => session,
+        Some(Err(e)) => {
+            eprintln!("Failed to load session for websocket: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Error loading session").into_response();
+        }
+        _ => return (StatusCode::UNAUTHORIZED, "Not a valid session").into_response(),
+    };
+
+    // Broadcasts are rendered for whoever is signed in, so the user is needed up front
+    let user = get_user_from_session(&session).ok().flatten();
+    let address = client_address(&headers, peer);
+
+    let upgrade = upgrade.protocols(Protocol::ALL.map(|protocol| protocol.name()));
+
+    upgrade.on_upgrade(move |websocket| {
+        let protocol = websocket
+            .protocol()
+            .and_then(|protocol| protocol.to_str().ok())
+            .and_then(Protocol::from_name)
+            .unwrap_or(Protocol::Html);
+
+        handle_websocket(
+            websocket,
+            state,
+            session_id,
+            user,
+            protocol,
+            address,
+            query.last_message_id,
+        )
+    })
+}
+
+/// Tells a websocket why it wasn't accepted, and closes it
+pub async fn refuse(mut websocket: WebSocket, address: IpAddr, refused: ConnectionRefused) {
+    println!("Refused websocket from {}: {:?}", address, refused);
+
+    let _ = websocket
+        .send(Message::Close(Some(refused.close_frame())))
+        .await;
+}
+
+/// Renders the messages a reconnecting websocket missed, or nothing if it didn't miss any
+fn render_replay(
+    missed: Vec<message::Message>,
+    protocol: Protocol,
+    user: Option<&User>,
+) -> Option<Payload> {
+    match protocol {
+        _ if missed.is_empty() => None,
+        Protocol::Html => Some(Payload::Html(
+            missed
+                .into_iter()
+                .filter_map(|message| render_event_for(&Event::MessageCreated(message), user))
+                .collect(),
+        )),
+        Protocol::Json => Some(Payload::Data(json!({ "messages": missed }))),
+    }
+}
+
+async fn handle_websocket(
+    mut websocket: WebSocket,
+    state: AppState,
+    session_id: Option<String>,
+    user: Option<User>,
+    protocol: Protocol,
+    address: IpAddr,
+    last_message_id: Option<i32>,
+) {
+    let websocket_handler: &'static Mutex<WebSocketHandler> = state.websocket_handler;
+
+    // Websockets which would be refused are turned away before anything is loaded for them
+    let accepted = websocket_handler.lock().unwrap().can_accept(address);
+
+    if let Err(refused) = accepted {
+        return refuse(websocket, address, refused).await;
+    }
+
+    let stored = load_missed_messages(websocket_handler, last_message_id).await;
+
+    let added = {
+        let mut websocket_handler = websocket_handler.lock().unwrap();
+
+        // Worked out under the same lock as broadcasts, so messages broadcast before the websocket
+        // was added are replayed, later ones are sent live, and none are sent twice
+        let missed = match last_message_id {
+            Some(last_message_id) => websocket_handler.replay.missed(last_message_id, stored),
+            None => vec![],
+        };
+
+        websocket_handler
+            .add_websocket(session_id, user.clone(), protocol, address)
+            .map(|(id, messages)| {
+                (
+                    id,
+                    messages,
+                    missed,
+                    websocket_handler.watch_user(id),
+                    websocket_handler.len(),
+                    websocket_handler.ping_interval,
+                    websocket_handler.idle_timeout,
+                )
+            })
+    };
+
+    let (id, mut messages, missed, watched_user, connected, ping_interval, idle_timeout) =
+        match added {
+            Ok(added) => added,
+            Err(refused) => return refuse(websocket, address, refused).await,
+        };
+
+    println!("Accepted websocket {}, {} connected", id, connected);
+
+    // Rendered without holding up broadcasts, and written before any of them, as they wait in the
+    // queue until the writer starts
+    let connected = json!({ "version": PROTOCOL_VERSION, "protocol": protocol.name() });
+    let mut opening = vec![frame(CONNECTED_FRAME, &Payload::Data(connected))];
+
+    if let Some(replay) = render_replay(missed, protocol, user.as_ref()) {
+        opening.push(frame(REPLAY_FRAME, &replay));
+    }
+
+    for frame in opening {
+        if websocket.send(Message::Text(frame)).await.is_err() {
+            websocket_handler.lock().unwrap().remove_websocket(id);
+            return;
+        }
+    }
+
+    let (mut sender, mut receiver) = websocket.split();
+
+    // Writes happen on their own task, so broadcasting never waits on the network
+    let mut writer = tokio::spawn(async move {
+        let mut pings = tokio::time::interval_at(Instant::now() + ping_interval, ping_interval);
+
+        loop {
+            let message = tokio::select! {
+                message = messages.recv() => match message {
+                    Some(message) => Message::Text(message),
+                    None => break,
+                },
+                _ = pings.tick() => Message::Ping(vec![]),
+            };
+
+            if sender.send(message).await.is_err() {
+                return;
+            }
+        }
+
+        // The handler dropped the websocket for being too slow
+        let close = CloseFrame {
+            code: CLOSE_TRY_AGAIN_LATER,
+            reason: "Too far behind".into(),
+        };
+
+        let _ = sender.send(Message::Close(Some(close))).await;
+    });
+
+    let mut connection = Connection {
+        id,
+        state,
+        protocol,
+        user: watched_user.expect("The websocket was just added"),
+        last_typed: None,
+    };
+
+    // Anything sent by the client, including pongs, shows it's still there
+    let mut reader = tokio::spawn(async move {
+        loop {
+            let message = match tokio::time::timeout(idle_timeout, receiver.next()).await {
+                Ok(Some(Ok(message))) => message,
+                Ok(_) => break,
+                Err(_) => {
+                    println!("Closing websocket {} after it went quiet", connection.id);
+                    break;
+                }
+            };
+
+            match message {
+                Message::Text(text) => connection.handle(&text).await,
+                Message::Close(_) => break,
+                _ => {}
+            }
+        }
+    });
+
+    // Whichever half finishes first takes the other down with it
+    tokio::select! {
+        _ = &mut writer => reader.abort(),
+        _ = &mut reader => writer.abort(),
+    }
+
+    let connected = {
+        let mut websocket_handler = websocket_handler.lock().unwrap();
+        websocket_handler.remove_websocket(id);
+        websocket_handler.len()
+    };
+
+    println!("Closed websocket {}, {} connected", id, connected);
+}
+
+/// Regularly removes websockets which finished without being removed
+pub async fn run_reaper(websocket_handler: &'static Mutex<WebSocketHandler>) {
+    let mut interval = tokio::time::interval(PING_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let reaped = websocket_handler.lock().unwrap().reap();
+
+        if reaped > 0 {
+            println!("Reaped {} closed websockets", reaped);
+        }
+    }
 }